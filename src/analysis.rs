@@ -0,0 +1,605 @@
+use std::cmp;
+use std::fmt;
+use std::collections::{BTreeMap, BTreeSet};
+use pqgrams::{PQGram, Profile, Node, ValidGramElement, LabelledTree, pqgram_profile, pqgram_distance, _check_compatible};
+use pygram::write_json_string;
+
+/// Count, for each distinct gram, how many of the given profiles contain it
+/// at least once.
+fn document_frequencies<L: ValidGramElement>(profiles: &[Profile<L>]) -> BTreeMap<&PQGram<L>, usize> {
+    let mut freqs = BTreeMap::new();
+    for profile in profiles {
+        let distinct: BTreeSet<&PQGram<L>> = profile.iter().collect();
+        for gram in distinct {
+            *freqs.entry(gram).or_insert(0) += 1;
+        }
+    }
+    freqs
+}
+
+/// Panic unless every profile in the groups was built with the same p and q.
+fn check_groups<L: ValidGramElement>(groups: &[&[Profile<L>]]) {
+    let mut profiles = groups.iter().flat_map(|g| g.iter());
+    if let Some(first) = profiles.next() {
+        for profile in profiles {
+            _check_compatible(first, profile);
+        }
+    }
+}
+
+/// Rank the grams that best separate two groups of trees, given their profiles.
+/// Each gram is scored by the difference between the fraction of `left` profiles
+/// and the fraction of `right` profiles that contain it, so a score of 1.0 means
+/// "in every left tree and no right tree" and -1.0 the reverse. Results are ordered
+/// by descending absolute score; grams that are equally common in both groups
+/// score zero and sort last. Panics if the profiles were built with different p or q.
+pub fn discriminative_grams<L: ValidGramElement>(left: &[Profile<L>], right: &[Profile<L>]) -> Vec<(PQGram<L>, f64)> {
+    check_groups(&[left, right]);
+    let left_freqs = document_frequencies(left);
+    let right_freqs = document_frequencies(right);
+    let left_n = group_size(left.len());
+    let right_n = group_size(right.len());
+    let grams: BTreeSet<&PQGram<L>> = left_freqs.keys().chain(right_freqs.keys()).cloned().collect();
+    let mut scored: Vec<(PQGram<L>, f64)> = grams.into_iter().map(|gram| {
+        let l = *left_freqs.get(gram).unwrap_or(&0) as f64 / left_n;
+        let r = *right_freqs.get(gram).unwrap_or(&0) as f64 / right_n;
+        (gram.clone(), l - r)
+    }).collect();
+    // Stable sort keeps ties in gram order, so reports are deterministic.
+    scored.sort_by(|a, b| b.1.abs().partial_cmp(&a.1.abs()).expect("Scores are never NaN"));
+    scored
+}
+
+/// Group sizes as a divisor; an empty group contributes zero frequency rather than NaN.
+fn group_size(n: usize) -> f64 {
+    if n == 0 { 1. } else { n as f64 }
+}
+
+/// The mean multiplicity of each gram over a set of example profiles.
+#[derive(Clone, Debug)]
+struct Centroid<L: ValidGramElement> {
+    counts: BTreeMap<PQGram<L>, f64>,
+    size: f64,
+}
+
+impl<L: ValidGramElement> Centroid<L> {
+    fn from_profiles(profiles: &[Profile<L>]) -> Centroid<L> {
+        let n = group_size(profiles.len());
+        let mut counts = BTreeMap::new();
+        for gram in profiles.iter().flat_map(|p| p.iter()) {
+            *counts.entry(gram.clone()).or_insert(0.) += 1. / n;
+        }
+        let size = counts.values().sum();
+        Centroid{counts, size}
+    }
+
+    /// Same shape as pqgram_distance: one minus twice the bag intersection over
+    /// the summed sizes, using fractional counts on the centroid side.
+    fn distance(&self, profile: &[PQGram<L>]) -> f64 {
+        let mut query: BTreeMap<&PQGram<L>, f64> = BTreeMap::new();
+        for gram in profile {
+            *query.entry(gram).or_insert(0.) += 1.;
+        }
+        let intersection: f64 = query.iter()
+                                     .map(|(gram, n)| n.min(*self.counts.get(*gram).unwrap_or(&0.)))
+                                     .sum();
+        let union = profile.len() as f64 + self.size;
+        if union == 0. { return 0. }
+        1. - 2. * (intersection / union)
+    }
+}
+
+/// Minimal nearest-centroid classifier over PQGram profiles. Each class is
+/// summarised by the mean gram counts of its example profiles, and a query
+/// profile is assigned to the class whose centroid is closest. The classifier takes
+/// its p and q from the first example it is given, and panics on any example or
+/// query profile built with others.
+#[derive(Clone, Debug)]
+pub struct NearestCentroid<C, L: ValidGramElement> {
+    classes: Vec<(C, Centroid<L>)>,
+    parameters: Option<(usize, usize)>,
+}
+
+impl<C, L: ValidGramElement> Default for NearestCentroid<C, L> {
+    fn default() -> NearestCentroid<C, L> {
+        NearestCentroid{classes: vec![], parameters: None}
+    }
+}
+
+impl<C, L: ValidGramElement> NearestCentroid<C, L> {
+    pub fn new() -> NearestCentroid<C, L> {
+        NearestCentroid::default()
+    }
+
+    /// The p and q of the examples so far, or None before any were added.
+    pub fn parameters(&self) -> Option<(usize, usize)> {
+        self.parameters
+    }
+
+    fn check_parameters(&self, profile: &Profile<L>) {
+        if let Some((p, q)) = self.parameters {
+            assert!(profile.p() == p && profile.q() == q,
+                    "cannot compare profiles built with different parameters: p = {}, q = {} and p = {}, q = {}",
+                    p, q, profile.p(), profile.q());
+        }
+    }
+
+    /// Builder-pattern helper adding a class from its example profiles.
+    pub fn add_class(mut self, class: C, examples: &[Profile<L>]) -> NearestCentroid<C, L> {
+        for example in examples {
+            self.check_parameters(example);
+            self.parameters = Some((example.p(), example.q()));
+        }
+        self.classes.push((class, Centroid::from_profiles(examples)));
+        self
+    }
+
+    /// Return the nearest class and its distance, or None if no classes were added.
+    /// Ties go to the class added first.
+    pub fn classify(&self, profile: &Profile<L>) -> Option<(&C, f64)> {
+        self.check_parameters(profile);
+        let mut best: Option<(&C, f64)> = None;
+        for (class, centroid) in &self.classes {
+            let d = centroid.distance(profile);
+            match best {
+                Some((_, bd)) if bd <= d => {},
+                _ => best = Some((class, d)),
+            }
+        }
+        best
+    }
+}
+
+/// A breakdown of why two profiles differ, suitable for rendering in reports.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
+pub struct Explanation<L: ValidGramElement> {
+    /// Bag-semantics pq-gram distance between the two profiles.
+    pub distance: f64,
+    /// Every gram whose multiplicity differs, with its count on the left and on the
+    /// right, ordered by descending count difference.
+    pub differing_grams: Vec<(PQGram<L>, usize, usize)>,
+    /// The number of unmatched grams anchored at each label, ordered by descending
+    /// contribution.
+    pub anchor_contributions: Vec<(Node<L>, usize)>,
+    /// The smaller profile's size over the larger's, 1 for equal sizes. Disjoint
+    /// profiles are all at distance 1, so this breaks ties when ranking them: a
+    /// candidate of similar size is structurally closer than one far larger or smaller.
+    pub size_ratio: f64,
+}
+
+fn gram_counts<L: ValidGramElement>(profile: &[PQGram<L>]) -> BTreeMap<&PQGram<L>, usize> {
+    let mut counts = BTreeMap::new();
+    for gram in profile {
+        *counts.entry(gram).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Explain the distance between two profiles: which grams are unmatched, and which
+/// anchors those grams belong to. Profiles need not be sorted. Panics if they were
+/// built with different p or q.
+pub fn explain<L: ValidGramElement>(left: &Profile<L>, right: &Profile<L>) -> Explanation<L> {
+    _check_compatible(left, right);
+    let left_counts = gram_counts(left);
+    let right_counts = gram_counts(right);
+    let grams: BTreeSet<&PQGram<L>> = left_counts.keys().chain(right_counts.keys()).cloned().collect();
+    let mut intersection = 0;
+    let mut differing_grams = Vec::new();
+    let mut anchors: BTreeMap<Node<L>, usize> = BTreeMap::new();
+    for gram in grams {
+        let l = *left_counts.get(gram).unwrap_or(&0);
+        let r = *right_counts.get(gram).unwrap_or(&0);
+        intersection += l.min(r);
+        if l != r {
+            *anchors.entry(gram.anchor().clone()).or_insert(0) += l.max(r) - l.min(r);
+            differing_grams.push((gram.clone(), l, r));
+        }
+    }
+    differing_grams.sort_by_key(|&(_, l, r)| cmp::Reverse(l.max(r) - l.min(r)));
+    let mut anchor_contributions: Vec<(Node<L>, usize)> = anchors.into_iter().collect();
+    anchor_contributions.sort_by_key(|&(_, n)| cmp::Reverse(n));
+    let union = left.len() + right.len();
+    let distance = if union == 0 { 0. } else { 1. - 2. * (intersection as f64 / union as f64) };
+    let (smaller, larger) = (left.len().min(right.len()), left.len().max(right.len()));
+    let size_ratio = if larger == 0 { 1. } else { smaller as f64 / larger as f64 };
+    Explanation{distance, differing_grams, anchor_contributions, size_ratio}
+}
+
+/// How far one version of a document has moved from the version before it and
+/// from the first (baseline) version.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
+pub struct VersionDrift {
+    pub version: usize,
+    pub from_previous: f64,
+    pub from_baseline: f64,
+}
+
+/// Tracks successive versions of one logical document, e.g. snapshots of a scraped
+/// page, recording the drift of each version as it is added. Only the baseline and
+/// latest profiles are kept, so long histories stay cheap.
+#[derive(Clone, Debug)]
+pub struct VersionedTree<L: ValidGramElement> {
+    p: usize,
+    q: usize,
+    baseline: Profile<L>,
+    previous: Profile<L>,
+    drift: Vec<VersionDrift>,
+}
+
+impl<L: ValidGramElement> VersionedTree<L> {
+    pub fn new(p: usize, q: usize) -> VersionedTree<L> {
+        VersionedTree{p, q, baseline: Profile::new(vec![], p, q), previous: Profile::new(vec![], p, q), drift: vec![]}
+    }
+
+    /// Add the next version of the document, returning its drift. The first
+    /// version becomes the baseline and has zero drift.
+    pub fn push<T: LabelledTree<L>>(&mut self, tree: T) -> VersionDrift {
+        let profile = pqgram_profile(tree, self.p, self.q, true);
+        let version = self.drift.len();
+        let drift = if version == 0 {
+            self.baseline = profile.clone();
+            VersionDrift{version, from_previous: 0., from_baseline: 0.}
+        } else {
+            VersionDrift{
+                version,
+                from_previous: pqgram_distance(&self.previous, &profile),
+                from_baseline: pqgram_distance(&self.baseline, &profile),
+            }
+        };
+        self.previous = profile;
+        self.drift.push(drift);
+        drift
+    }
+
+    /// The drift of every version so far, oldest first.
+    pub fn drift(&self) -> &[VersionDrift] {
+        &self.drift
+    }
+
+    pub fn len(&self) -> usize {
+        self.drift.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.drift.is_empty()
+    }
+}
+
+/// Each distinct gram in a profile with its number of occurrences, most frequent
+/// first. Ties are kept in gram order.
+pub fn profile_histogram<L: ValidGramElement>(profile: &[PQGram<L>]) -> Vec<(PQGram<L>, usize)> {
+    let mut histogram: Vec<(PQGram<L>, usize)> = gram_counts(profile).into_iter().map(|(g, n)| (g.clone(), n)).collect();
+    histogram.sort_by_key(|&(_, n)| cmp::Reverse(n));
+    histogram
+}
+
+impl<L: ValidGramElement> Profile<L> {
+    /// As profile_histogram.
+    pub fn histogram(&self) -> Vec<(PQGram<L>, usize)> {
+        profile_histogram(self)
+    }
+}
+
+fn csv_field(out: &mut String, field: &str) {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
+
+/// Write a histogram as CSV: one row per gram, one column per label (with filler_as
+/// standing in for filler nodes), and a final count column.
+pub fn histogram_to_csv<L: ValidGramElement + fmt::Display>(histogram: &[(PQGram<L>, usize)], filler_as: L) -> String {
+    let mut out = String::new();
+    for &(ref gram, count) in histogram {
+        for label in gram.concat(filler_as.clone()) {
+            csv_field(&mut out, &label.to_string());
+            out.push(',');
+        }
+        out.push_str(&count.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+/// Write a histogram of a string profile as a JSON array of `{"gram": [...], "count": n}`
+/// objects, with "*" for filler nodes as in PyGram.
+pub fn histogram_to_json(histogram: &[(PQGram<String>, usize)]) -> String {
+    let mut out = String::from("[");
+    for (i, &(ref gram, count)) in histogram.iter().enumerate() {
+        if i > 0 { out.push_str(", ") }
+        out.push_str("{\"gram\": [");
+        for (j, label) in gram.concat("*".to_string()).iter().enumerate() {
+            if j > 0 { out.push_str(", ") }
+            write_json_string(&mut out, label);
+        }
+        out.push_str(&format!("], \"count\": {}}}", count));
+    }
+    out.push(']');
+    out
+}
+
+/// Gram counts summed over a collection of profiles, with the total number of grams.
+fn aggregate_counts<L: ValidGramElement>(profiles: &[Profile<L>]) -> (BTreeMap<&PQGram<L>, usize>, usize) {
+    let mut counts = BTreeMap::new();
+    let mut total = 0;
+    for gram in profiles.iter().flat_map(|p| p.iter()) {
+        *counts.entry(gram).or_insert(0) += 1;
+        total += 1;
+    }
+    (counts, total)
+}
+
+/// Jensen-Shannon divergence, in bits, between the aggregate gram distributions of
+/// two collections of profiles: 0 when the collections use grams in identical
+/// proportions, 1 when they share no grams. Complements pairwise distances when
+/// monitoring a whole dataset for drift. Empty collections are treated as identical
+/// to each other and maximally divergent from anything else. Panics if the profiles
+/// were built with different p or q.
+pub fn corpus_divergence<L: ValidGramElement>(left: &[Profile<L>], right: &[Profile<L>]) -> f64 {
+    check_groups(&[left, right]);
+    let (left_counts, left_total) = aggregate_counts(left);
+    let (right_counts, right_total) = aggregate_counts(right);
+    if left_total == 0 || right_total == 0 {
+        return if left_total == right_total { 0. } else { 1. }
+    }
+    // Each gram contributes p*log(p/m) + q*log(q/m), halved, where m is the mean of p and q.
+    let term = |x: f64, m: f64| if x == 0. { 0. } else { x * (x / m).log2() };
+    let grams: BTreeSet<&PQGram<L>> = left_counts.keys().chain(right_counts.keys()).cloned().collect();
+    let divergence: f64 = grams.into_iter().map(|gram| {
+        let p = *left_counts.get(gram).unwrap_or(&0) as f64 / left_total as f64;
+        let q = *right_counts.get(gram).unwrap_or(&0) as f64 / right_total as f64;
+        let m = (p + q) / 2.;
+        (term(p, m) + term(q, m)) / 2.
+    }).sum();
+    // Rounding can leave tiny negative or above-one values.
+    divergence.clamp(0., 1.)
+}
+
+fn bag_intersection<L: ValidGramElement>(left: &[PQGram<L>], right: &[PQGram<L>]) -> usize {
+    let right_counts = gram_counts(right);
+    gram_counts(left).into_iter()
+                     .map(|(g, n)| n.min(*right_counts.get(g).unwrap_or(&0)))
+                     .sum()
+}
+
+/// Bag-semantics pq-gram distance between two profiles, which need not be sorted.
+fn bag_distance<L: ValidGramElement>(left: &[PQGram<L>], right: &[PQGram<L>]) -> f64 {
+    let union = left.len() + right.len();
+    if union == 0 { return 0. }
+    1. - 2. * (bag_intersection(left, right) as f64 / union as f64)
+}
+
+/// The fraction of inner's grams that also occur in outer, counting repeats: 1 when
+/// inner is essentially a subtree or subtemplate of outer, whatever else outer
+/// holds. Unlike the symmetric distance this is not diluted by the size of outer. An
+/// empty inner is contained in anything. Profiles need not be sorted. Panics if they
+/// were built with different p or q.
+pub fn containment<L: ValidGramElement>(inner: &Profile<L>, outer: &Profile<L>) -> f64 {
+    _check_compatible(inner, outer);
+    if inner.is_empty() { return 1. }
+    bag_intersection(inner, outer) as f64 / inner.len() as f64
+}
+
+/// Predict the class of a query profile by distance-weighted voting among its k
+/// nearest labelled examples, each voting with weight 1 - distance. If every
+/// neighbour is maximally distant the nearest one decides; remaining ties go to the
+/// smallest class. Returns None when there are no examples or k is zero. Panics if
+/// an example was built with a different p or q from the query.
+pub fn predict_label<'a, C, L>(examples: &'a [(C, Profile<L>)], query: &Profile<L>, k: usize) -> Option<&'a C>
+    where C: Ord, L: ValidGramElement
+{
+    for (_, example) in examples {
+        _check_compatible(query, example);
+    }
+    let mut neighbours: Vec<(f64, &C)> = examples.iter().map(|(c, p)| (bag_distance(query, p), c)).collect();
+    neighbours.sort_by(|a, b| a.0.partial_cmp(&b.0).expect("Distances are never NaN").then(a.1.cmp(b.1)));
+    neighbours.truncate(k);
+    let mut votes: BTreeMap<&C, f64> = BTreeMap::new();
+    for &(d, c) in &neighbours {
+        *votes.entry(c).or_insert(0.) += 1. - d;
+    }
+    let mut best: Option<(&C, f64)> = None;
+    for (c, v) in votes {
+        match best {
+            Some((_, bv)) if bv >= v => {},
+            _ => best = Some((c, v)),
+        }
+    }
+    match best {
+        Some((c, v)) if v > 0. => Some(c),
+        _ => neighbours.first().map(|&(_, c)| c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use default_tree::Tree;
+    use pqgrams::{pqgram_profile, Node, Profile};
+    use super::{VersionedTree, profile_histogram, histogram_to_csv, histogram_to_json, corpus_divergence, predict_label, containment};
+    use super::{discriminative_grams, explain, NearestCentroid};
+
+    #[test]
+    fn test_discriminative_grams() {
+        let with_x = vec![
+            pqgram_profile(Tree::new_str("a").add_node(Tree::new_str("x")), 2, 2, true),
+            pqgram_profile(Tree::new_str("a").add_node(Tree::new_str("x")).add_node(Tree::new_str("b")), 2, 2, true),
+        ];
+        let without_x = vec![
+            pqgram_profile(Tree::new_str("a").add_node(Tree::new_str("b")), 2, 2, true),
+        ];
+        let report: Vec<(Vec<String>, f64)> = discriminative_grams(&with_x, &without_x)
+            .into_iter()
+            .map(|(g, s)| (g.concat("*".to_string()), s))
+            .collect();
+        let score_of = |gram: &[&str]| report.iter().find(|entry| entry.0 == gram).unwrap().1;
+        assert_eq!(score_of(&["a", "x", "*", "*"]), 1.);
+        assert_eq!(score_of(&["*", "a", "*", "b"]), -1.);
+        assert_eq!(score_of(&["*", "a", "x", "*"]), 0.5);
+        assert_eq!(score_of(&["a", "b", "*", "*"]), -0.5);
+        assert_eq!(report[0].1.abs(), 1.);
+        assert_eq!(report.last().unwrap().1.abs(), 0.5);
+    }
+
+    #[test]
+    fn test_nearest_centroid() {
+        let list = |items: &[&str]| items.iter().fold(Tree::new_str("ul"), |t, i| t.add_node(Tree::new_str("li").add_node(Tree::new_str(i))));
+        let table = |cells: &[&str]| Tree::new_str("table").add_node(cells.iter().fold(Tree::new_str("tr"), |t, c| t.add_node(Tree::new_str(c))));
+        let classifier = NearestCentroid::new()
+            .add_class("list", &[pqgram_profile(list(&["a", "b"]), 2, 3, true),
+                                 pqgram_profile(list(&["a", "b", "c"]), 2, 3, true)])
+            .add_class("table", &[pqgram_profile(table(&["a", "b"]), 2, 3, true),
+                                  pqgram_profile(table(&["x", "y", "z"]), 2, 3, true)]);
+        let (class, _) = classifier.classify(&pqgram_profile(list(&["a", "z"]), 2, 3, true)).unwrap();
+        assert_eq!(*class, "list");
+        let (class, _) = classifier.classify(&pqgram_profile(table(&["a", "c"]), 2, 3, true)).unwrap();
+        assert_eq!(*class, "table");
+        let (_, dist) = classifier.classify(&pqgram_profile(table(&["a", "b"]), 2, 3, true)).unwrap();
+        assert!(dist > 0. && dist < 1.);
+        assert!(NearestCentroid::<&str, String>::new().classify(&Profile::new(vec![], 2, 3)).is_none());
+        assert_eq!(classifier.parameters(), Some((2, 3)));
+    }
+
+    #[test]
+    #[should_panic(expected = "different parameters")]
+    fn test_nearest_centroid_mismatched_parameters() {
+        let tree = || Tree::new_str("a").add_node(Tree::new_str("b"));
+        let classifier = NearestCentroid::new().add_class("a", &[pqgram_profile(tree(), 2, 3, true)]);
+        classifier.classify(&pqgram_profile(tree(), 1, 2, true));
+    }
+
+    #[test]
+    #[should_panic(expected = "different parameters")]
+    fn test_discriminative_grams_mismatched_parameters() {
+        let tree = || Tree::new_str("a").add_node(Tree::new_str("b"));
+        discriminative_grams(&[pqgram_profile(tree(), 2, 3, true)], &[pqgram_profile(tree(), 2, 2, true)]);
+    }
+
+    #[test]
+    fn test_explain() {
+        let left = Tree::new_str("a").add_node(Tree::new_str("b")).add_node(Tree::new_str("c"));
+        let right = Tree::new_str("a").add_node(Tree::new_str("b")).add_node(Tree::new_str("x"));
+        let explanation = explain(&pqgram_profile(left.clone(), 2, 2, false), &pqgram_profile(right, 2, 2, false));
+        assert_eq!((explanation.distance * 100.).round(), 60.);
+        assert_eq!(explanation.differing_grams.len(), 6);
+        assert!(explanation.differing_grams.iter().all(|&(_, l, r)| l + r == 1));
+        assert_eq!(explanation.anchor_contributions, vec![(Node::Label("a".to_string()), 4),
+                                                         (Node::Label("c".to_string()), 1),
+                                                         (Node::Label("x".to_string()), 1)]);
+        let same = explain(&pqgram_profile(left.clone(), 2, 2, false), &pqgram_profile(left.clone(), 2, 2, true));
+        assert_eq!(same.distance, 0.);
+        assert_eq!(same.size_ratio, 1.);
+        let disjoint = explain(&pqgram_profile(Tree::new_str("x"), 2, 2, false), &pqgram_profile(left, 2, 2, false));
+        assert_eq!((disjoint.distance, disjoint.size_ratio), (1., 0.2));
+        assert!(same.differing_grams.is_empty() && same.anchor_contributions.is_empty());
+    }
+
+    #[test]
+    fn test_versioned_tree() {
+        let v0 = Tree::new_str("a").add_node(Tree::new_str("b")).add_node(Tree::new_str("c"));
+        let v1 = v0.clone().add_node(Tree::new_str("d"));
+        let mut history = VersionedTree::new(2, 3);
+        assert!(history.is_empty());
+        assert_eq!(history.push(v0.clone()).from_baseline, 0.);
+        let d1 = history.push(v1.clone());
+        let d2 = history.push(v1);
+        let d3 = history.push(v0);
+        assert!(d1.from_previous > 0. && d1.from_previous == d1.from_baseline);
+        assert_eq!(d2.from_previous, 0.);
+        assert_eq!(d2.from_baseline, d1.from_baseline);
+        assert_eq!(d3.from_baseline, 0.);
+        assert_eq!(history.len(), 4);
+        assert_eq!(history.drift()[2], d2);
+    }
+
+    #[test]
+    fn test_profile_histogram() {
+        let tree = Tree::new_str("a").add_node(Tree::new_str("b")).add_node(Tree::new_str("b,c"));
+        let histogram = profile_histogram(&pqgram_profile(tree, 2, 2, false));
+        assert_eq!(histogram.len(), 5);
+        assert_eq!(histogram[0].0.concat("*".to_string()), vec!["*", "a", "*", "b"]);
+        assert!(histogram.iter().all(|&(_, n)| n == 1));
+        let leaves = Tree::new_str("a").add_node(Tree::new_str("b")).add_node(Tree::new_str("b"));
+        let histogram = profile_histogram(&pqgram_profile(leaves, 2, 2, false));
+        assert_eq!(histogram[0].1, 2);
+        assert_eq!(histogram_to_csv(&histogram[..1], "*".to_string()), "a,b,*,*,2\n");
+        assert_eq!(histogram_to_json(&histogram[..1]), r#"[{"gram": ["a", "b", "*", "*"], "count": 2}]"#);
+        let tree = Tree::new_str("a").add_node(Tree::new_str("b,\"c\""));
+        let histogram = profile_histogram(&pqgram_profile(tree, 2, 2, true));
+        assert!(histogram_to_csv(&histogram, "*".to_string()).contains(r#"*,a,*,"b,""c""",1"#));
+    }
+
+    #[test]
+    fn test_corpus_divergence() {
+        let lists = vec![
+            pqgram_profile(Tree::new_str("ul").add_node(Tree::new_str("li")), 2, 2, true),
+            pqgram_profile(Tree::new_str("ul").add_node(Tree::new_str("li")).add_node(Tree::new_str("li")), 2, 2, true),
+        ];
+        let tables = vec![pqgram_profile(Tree::new_str("table").add_node(Tree::new_str("tr")), 2, 2, true)];
+        let mixed = vec![lists[0].clone(), tables[0].clone()];
+        assert_eq!(corpus_divergence(&lists, &lists), 0.);
+        assert!((corpus_divergence(&lists, &tables) - 1.).abs() < 1e-12);
+        let d = corpus_divergence(&lists, &mixed);
+        assert!(d > 0. && d < 1.);
+        assert_eq!(d, corpus_divergence(&mixed, &lists));
+        assert_eq!(corpus_divergence::<String>(&[], &[]), 0.);
+        assert_eq!(corpus_divergence(&[], &lists), 1.);
+    }
+
+    #[test]
+    #[should_panic(expected = "different parameters")]
+    fn test_corpus_divergence_mismatched_parameters() {
+        let tree = || Tree::new_str("ul").add_node(Tree::new_str("li"));
+        corpus_divergence(&[pqgram_profile(tree(), 2, 2, true), pqgram_profile(tree(), 2, 3, true)], &[]);
+    }
+
+    #[test]
+    fn test_predict_label() {
+        let list = |n: usize| (0..n).fold(Tree::new_str("ul"), |t, _| t.add_node(Tree::new_str("li")));
+        let row = |n: usize| (0..n).fold(Tree::new_str("tr"), |t, _| t.add_node(Tree::new_str("td")));
+        let examples = vec![
+            ("list", pqgram_profile(list(2), 2, 3, true)),
+            ("list", pqgram_profile(list(3), 2, 3, true)),
+            ("list", pqgram_profile(list(5), 2, 3, true)),
+            ("row", pqgram_profile(row(2), 2, 3, true)),
+            ("row", pqgram_profile(row(3), 2, 3, true)),
+        ];
+        assert_eq!(predict_label(&examples, &pqgram_profile(list(4), 2, 3, true), 3), Some(&"list"));
+        assert_eq!(predict_label(&examples, &pqgram_profile(row(4), 2, 3, true), 3), Some(&"row"));
+        // Nothing in common with any example: the nearest (first, on ties) decides.
+        assert_eq!(predict_label(&examples, &pqgram_profile(Tree::new_str("x"), 2, 3, true), 3), Some(&"list"));
+        assert_eq!(predict_label(&examples, &pqgram_profile(list(4), 2, 3, true), 0), None);
+        assert_eq!(predict_label::<&str, String>(&[], &pqgram_profile(list(4), 2, 3, true), 3), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "different parameters")]
+    fn test_predict_label_mismatched_parameters() {
+        let tree = || Tree::new_str("ul").add_node(Tree::new_str("li"));
+        predict_label(&[("list", pqgram_profile(tree(), 2, 3, true))], &pqgram_profile(tree(), 1, 2, true), 1);
+    }
+
+    #[test]
+    fn test_containment() {
+        let inner = Tree::new_str("form").add_node(Tree::new_str("input")).add_node(Tree::new_str("button"));
+        let outer = Tree::new_str("body").add_node(Tree::new_str("h1")).add_node(inner.clone());
+        let inner = pqgram_profile(inner, 1, 2, true);
+        let outer = pqgram_profile(outer, 1, 2, true);
+        assert_eq!(containment(&inner, &outer), 1.);
+        assert!(containment(&outer, &inner) < 1.);
+        assert_eq!(containment(&Profile::new(vec![], 1, 2), &inner), 1.);
+        assert_eq!(containment(&inner, &Profile::new(vec![], 1, 2)), 0.);
+    }
+
+    #[test]
+    #[should_panic(expected = "different parameters")]
+    fn test_containment_mismatched_parameters() {
+        let tree = || Tree::new_str("form").add_node(Tree::new_str("input"));
+        containment(&pqgram_profile(tree(), 2, 3, true), &pqgram_profile(tree(), 1, 2, true));
+    }
+}
@@ -10,7 +10,7 @@ pub struct BDeque<T: Clone> {
 impl<T: Clone> BDeque<T> {
     pub fn new(maxlen: usize) -> BDeque<T> {
         let vd = vec_deque::VecDeque::with_capacity(maxlen);
-        BDeque{maxlen: maxlen, state: vd}
+        BDeque{maxlen, state: vd}
     }
 
     pub fn push_back(&mut self, item: T) -> Option<T> {
@@ -20,7 +20,7 @@ impl<T: Clone> BDeque<T> {
             None
         };
         self.state.push_back(item);
-        return i
+        i
     }
 
     pub fn fill_with(&mut self, item: T)
@@ -45,7 +45,7 @@ mod tests {
     #[test]
     fn test_bdeque() {
         let mut bd = BDeque::new(3);
-        assert_eq!(bd.copy_state(), vec![]);
+        assert_eq!(bd.copy_state(), Vec::<i32>::new());
         bd.push_back(1);
         assert_eq!(bd.copy_state(), vec![1]);
         bd.push_back(2);
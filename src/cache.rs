@@ -0,0 +1,124 @@
+use std::collections::{BTreeMap, HashMap};
+use default_tree::{Tree, CanonicalLabel};
+use pqgrams::{Profile, ValidGramElement, pqgram_profile_ref, pqgram_distance};
+
+/// Storage for profiles keyed by Tree::profile_key, so repeated comparisons of the
+/// same documents can skip profiling. Implement this for your own backend.
+pub trait ProfileCache<L: ValidGramElement> {
+    fn get(&mut self, key: &[u8]) -> Option<Profile<L>>;
+    fn put(&mut self, key: Vec<u8>, profile: Profile<L>);
+}
+
+/// An in-memory ProfileCache holding at most `capacity` profiles, evicting the least
+/// recently used when full.
+#[derive(Clone, Debug)]
+pub struct LruProfileCache<L: ValidGramElement> {
+    capacity: usize,
+    clock: u64,
+    entries: HashMap<Vec<u8>, (u64, Profile<L>)>,
+    recency: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<L: ValidGramElement> LruProfileCache<L> {
+    pub fn new(capacity: usize) -> LruProfileCache<L> {
+        LruProfileCache{capacity, clock: 0, entries: HashMap::new(), recency: BTreeMap::new()}
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+impl<L: ValidGramElement> ProfileCache<L> for LruProfileCache<L> {
+    fn get(&mut self, key: &[u8]) -> Option<Profile<L>> {
+        let now = self.tick();
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.0);
+        entry.0 = now;
+        self.recency.insert(now, key.to_vec());
+        Some(entry.1.clone())
+    }
+
+    fn put(&mut self, key: Vec<u8>, profile: Profile<L>) {
+        if self.capacity == 0 {
+            return
+        }
+        let now = self.tick();
+        if let Some((stamp, _)) = self.entries.remove(&key) {
+            self.recency.remove(&stamp);
+        } else if self.entries.len() >= self.capacity {
+            let oldest = self.recency.keys().next().cloned();
+            if let Some(stamp) = oldest {
+                let evicted = self.recency.remove(&stamp).expect("Recency entry just seen");
+                self.entries.remove(&evicted);
+            }
+        }
+        self.recency.insert(now, key.clone());
+        self.entries.insert(key, (now, profile));
+    }
+}
+
+/// The sorted profile of a tree, fetched from the cache if present and profiled and
+/// stored otherwise.
+pub fn cached_profile<L, C>(cache: &mut C, tree: &Tree<L>, p: usize, q: usize) -> Profile<L>
+    where L: ValidGramElement + CanonicalLabel, C: ProfileCache<L>
+{
+    let key = tree.profile_key(p, q, None);
+    if let Some(profile) = cache.get(&key) {
+        return profile
+    }
+    let profile = pqgram_profile_ref(tree, p, q, true);
+    cache.put(key, profile.clone());
+    profile
+}
+
+/// As pqgram_distance on the two trees' profiles, profiling through the cache.
+pub fn cached_distance<L, C>(cache: &mut C, left: &Tree<L>, right: &Tree<L>, p: usize, q: usize) -> f64
+    where L: ValidGramElement + CanonicalLabel, C: ProfileCache<L>
+{
+    let left = cached_profile(cache, left, p, q);
+    let right = cached_profile(cache, right, p, q);
+    pqgram_distance(&left, &right)
+}
+
+#[cfg(test)]
+mod tests {
+    use default_tree::Tree;
+    use pqgrams::pqgram_profile;
+    use tests::deep_chain;
+    use super::{LruProfileCache, ProfileCache, cached_profile, cached_distance};
+
+    #[test]
+    fn test_lru_profile_cache() {
+        let a = Tree::new_str("a").add_node(Tree::new_str("b"));
+        let b = Tree::new_str("b").add_node(Tree::new_str("c"));
+        let c = Tree::new_str("c");
+        let mut cache = LruProfileCache::new(2);
+        assert_eq!(cached_profile(&mut cache, &a, 2, 3), pqgram_profile(a.clone(), 2, 3, true));
+        assert!(cache.get(&a.profile_key(2, 3, None)).is_some());
+        assert!(cache.get(&a.profile_key(2, 2, None)).is_none());
+        cached_profile(&mut cache, &b, 2, 3);
+        // a was used more recently than b, so b is evicted.
+        cache.get(&a.profile_key(2, 3, None));
+        cached_profile(&mut cache, &c, 2, 3);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&b.profile_key(2, 3, None)).is_none());
+        assert!(cache.get(&a.profile_key(2, 3, None)).is_some());
+        assert_eq!(cached_distance(&mut cache, &a, &a, 2, 3), 0.);
+        assert_eq!(cached_distance(&mut cache, &a, &c, 2, 3), 1.);
+        // Keying a tree must not recurse on trees too deep for that.
+        let chain = deep_chain(100_000);
+        let mut cache = LruProfileCache::new(1);
+        assert_eq!(cached_profile(&mut cache, &chain, 2, 3).len(), 300_001);
+        assert_eq!(cached_distance(&mut cache, &chain, &chain, 2, 3), 0.);
+    }
+}
@@ -0,0 +1,124 @@
+use std::vec;
+use pqgrams::{pqgram_profile_ref, pqgram_distance, LabelledTree, ValidGramElement};
+
+/// A node whose children are still being walked: the children left, the size of the
+/// subtree so far, and how many chunks there were when the node was reached.
+struct Visit<'a, T: 'a> {
+    node: &'a T,
+    children: vec::IntoIter<&'a T>,
+    size: usize,
+    start: usize,
+}
+
+/// Push the chunks below subtree onto chunks in document order, returning the size of
+/// subtree. Walks with an explicit stack, so that deep trees can be chunked too.
+fn _chunk_subtree<'a, L, T>(subtree: &'a T, min_nodes: usize, max_nodes: usize, chunks: &mut Vec<&'a T>) -> usize
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    let mut pending = vec![Visit{node: subtree, children: subtree.children().into_iter(), size: 1, start: chunks.len()}];
+    loop {
+        let next = pending.last_mut().expect("The subtree is visited last").children.next();
+        if let Some(child) = next {
+            pending.push(Visit{node: child, children: child.children().into_iter(), size: 1, start: chunks.len()});
+            continue
+        }
+        let done = pending.pop().expect("The subtree is visited last");
+        if done.size <= max_nodes {
+            // Small enough to be a chunk itself, so none of its children are. Children
+            // of a subtree this small push nothing else, so only they are dropped.
+            chunks.truncate(done.start);
+        }
+        match pending.last_mut() {
+            Some(parent) => {
+                if done.size >= min_nodes && done.size <= max_nodes {
+                    chunks.push(done.node);
+                }
+                parent.size += done.size;
+            },
+            None => return done.size,
+        }
+    }
+}
+
+/// Split a tree into chunks: the largest subtrees holding at most max_nodes nodes.
+/// Chunks smaller than min_nodes are dropped, as tiny subtrees match too easily to
+/// be informative. Nodes above the chunks belong to no chunk. Chunks are returned in
+/// document order, so the same section of two documents chunks the same way
+/// regardless of what surrounds it.
+pub fn chunk_tree<L, T>(tree: &T, min_nodes: usize, max_nodes: usize) -> Vec<&T>
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    let mut chunks = Vec::new();
+    let size = _chunk_subtree(tree, min_nodes, max_nodes, &mut chunks);
+    if size >= min_nodes && size <= max_nodes {
+        chunks.push(tree);
+    }
+    chunks
+}
+
+/// Profile every chunk of two documents and return the (left index, right index,
+/// distance) of each chunk pair no further apart than max_distance, ordered by
+/// ascending distance. Useful for spotting sections copied between otherwise
+/// different documents.
+pub fn match_chunks<L, T>(left: &[&T], right: &[&T], p: usize, q: usize, max_distance: f64) -> Vec<(usize, usize, f64)>
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    let left_profiles: Vec<_> = left.iter().map(|c| pqgram_profile_ref(*c, p, q, true)).collect();
+    let right_profiles: Vec<_> = right.iter().map(|c| pqgram_profile_ref(*c, p, q, true)).collect();
+    let mut matches = Vec::new();
+    for (i, lp) in left_profiles.iter().enumerate() {
+        for (j, rp) in right_profiles.iter().enumerate() {
+            let distance = pqgram_distance(lp, rp);
+            if distance <= max_distance {
+                matches.push((i, j, distance));
+            }
+        }
+    }
+    matches.sort_by(|a, b| a.2.partial_cmp(&b.2).expect("Distances are never NaN"));
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use default_tree::Tree;
+    use tests::deep_chain;
+    use super::{chunk_tree, match_chunks};
+
+    fn section(title: &str) -> Tree<String> {
+        Tree::new_str("section")
+            .add_node(Tree::new_str("h1").add_node(Tree::new_str(title)))
+            .add_node(Tree::new_str("p").add_node(Tree::new_str("text")))
+    }
+
+    #[test]
+    fn test_chunk_and_match() {
+        let left = Tree::new_str("body").add_node(section("intro")).add_node(section("shared"));
+        let right = Tree::new_str("div").add_node(Tree::new_str("nav")).add_node(section("shared"));
+        let left_chunks = chunk_tree(&left, 2, 5);
+        let right_chunks = chunk_tree(&right, 2, 5);
+        assert_eq!(left_chunks.len(), 2);
+        assert_eq!(right_chunks.len(), 1);
+        assert!(left_chunks.iter().all(|c| c.label == "section"));
+        let matches = match_chunks(&left_chunks, &right_chunks, 2, 3, 0.5);
+        assert_eq!(matches[0], (1, 0, 0.));
+        assert!(chunk_tree(&left, 2, 100)[0].label == "body");
+    }
+
+    #[test]
+    fn test_chunks_in_document_order() {
+        let s = |text: &str| Tree::new_str("s").add_node(Tree::new_str(text)).add_node(Tree::new_str("p"));
+        let tree = Tree::new_str("body").add_node(s("first"))
+            .add_node(Tree::new_str("big").add_node(s("x")).add_node(s("y")));
+        let firsts: Vec<&str> = chunk_tree(&tree, 2, 4).iter().map(|c| c.children[0].label.as_str()).collect();
+        assert_eq!(firsts, vec!["first", "x", "y"]);
+    }
+
+    #[test]
+    fn test_chunk_deep_chain() {
+        // Only the bottom five nodes fit in a chunk, and the chunk is their top.
+        let chain = deep_chain(100_000);
+        let chunks = chunk_tree(&chain, 2, 5);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].label, 4);
+    }
+}
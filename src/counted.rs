@@ -0,0 +1,145 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use pqgrams::{PQGram, Profile, ValidGramElement};
+
+/// A profile held as the count of each distinct gram, for comparing large profiles
+/// without filler substitution or per-gram concatenation. Build one per stored
+/// document and compare with counted_pqgram_distance.
+#[derive(Clone,Debug,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(serialize = "L: ::serde::Serialize",
+                                          deserialize = "L: ::serde::Deserialize<'de>")))]
+#[cfg_attr(feature = "serde", serde(try_from = "CountedProfileData<L>"))]
+pub struct CountedProfile<L: ValidGramElement> {
+    // Grams aren't strings, so formats like JSON can't key a map by them.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_counts"))]
+    counts: BTreeMap<PQGram<L>, usize>,
+    len: usize,
+    p: usize,
+    q: usize,
+}
+
+/// CountedProfile as it is deserialized: counts as (gram, count) pairs, not yet
+/// checked against p and q. The stored len is read but not trusted; it is summed
+/// again from the counts.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct CountedProfileData<L: ValidGramElement> {
+    counts: Vec<(PQGram<L>, usize)>,
+    #[allow(dead_code)]
+    len: usize,
+    p: usize,
+    q: usize,
+}
+
+#[cfg(feature = "serde")]
+impl<L: ValidGramElement> ::std::convert::TryFrom<CountedProfileData<L>> for CountedProfile<L> {
+    type Error = String;
+
+    fn try_from(data: CountedProfileData<L>) -> Result<CountedProfile<L>, String> {
+        let (p, q) = (data.p, data.q);
+        if p == 0 || q == 0 {
+            return Err(format!("p and q must both be at least 1, got p = {}, q = {}", p, q))
+        }
+        let mut counts = BTreeMap::new();
+        let mut len: usize = 0;
+        for (gram, count) in data.counts {
+            if gram.ancestors().len() != p || gram.siblings().len() != q {
+                return Err(format!("gram {:?} was not built with p = {}, q = {}", gram, p, q))
+            }
+            if count == 0 {
+                return Err(format!("gram {:?} has a count of zero", gram))
+            }
+            len = len.checked_add(count).ok_or("gram counts overflow usize")?;
+            match counts.entry(gram) {
+                btree_map::Entry::Vacant(entry) => { entry.insert(count); },
+                btree_map::Entry::Occupied(entry) => return Err(format!("gram {:?} is counted twice", entry.key())),
+            }
+        }
+        Ok(CountedProfile{counts, len, p, q})
+    }
+}
+
+impl<L: ValidGramElement> CountedProfile<L> {
+    pub fn from_profile(profile: &Profile<L>) -> CountedProfile<L> {
+        let mut counts = BTreeMap::new();
+        for gram in profile {
+            *counts.entry(gram.clone()).or_insert(0) += 1;
+        }
+        CountedProfile{counts, len: profile.len(), p: profile.p(), q: profile.q()}
+    }
+
+    /// The number of grams counted, repeats included.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many times a gram occurs.
+    pub fn count(&self, gram: &PQGram<L>) -> usize {
+        *self.counts.get(gram).unwrap_or(&0)
+    }
+
+    /// Each distinct gram with its count, in PQGram order.
+    pub fn iter(&self) -> btree_map::Iter<'_, PQGram<L>, usize> {
+        self.counts.iter()
+    }
+}
+
+/// The pq-gram distance between two counted profiles, as pqgram_distance gives for
+/// the profiles they were counted from: the bag intersection is summed in a single
+/// merge over the distinct grams of each. Grams are compared whole, so no filler
+/// value is needed. Panics if the profiles were built with different p or q.
+pub fn counted_pqgram_distance<L: ValidGramElement>(left: &CountedProfile<L>, right: &CountedProfile<L>) -> f64 {
+    assert!(left.p == right.p && left.q == right.q,
+            "cannot compare profiles built with different parameters: p = {}, q = {} and p = {}, q = {}",
+            left.p, left.q, right.p, right.q);
+    let union = left.len + right.len;
+    if union == 0 { return 0. }
+    let mut lefts = left.counts.iter().peekable();
+    let mut rights = right.counts.iter().peekable();
+    let mut intersection = 0;
+    while let (Some(&(lg, ln)), Some(&(rg, rn))) = (lefts.peek(), rights.peek()) {
+        match lg.cmp(rg) {
+            cmp::Ordering::Equal => {
+                intersection += ln.min(rn);
+                lefts.next();
+                rights.next();
+            },
+            cmp::Ordering::Less => { lefts.next(); },
+            cmp::Ordering::Greater => { rights.next(); },
+        }
+    }
+    1. - 2. * (intersection as f64 / union as f64)
+}
+
+/// Serializes a gram count map as a sequence of (gram, count) pairs.
+#[cfg(feature = "serde")]
+fn serialize_counts<L, S>(counts: &BTreeMap<PQGram<L>, usize>, serializer: S) -> Result<S::Ok, S::Error>
+    where L: ValidGramElement + ::serde::Serialize, S: ::serde::Serializer
+{
+    serializer.collect_seq(counts.iter())
+}
+
+#[cfg(test)]
+mod tests {
+    use default_tree::Tree;
+    use pqgrams::{pqgram_profile, pqgram_distance};
+    use super::{CountedProfile, counted_pqgram_distance};
+
+    #[test]
+    fn test_counted_distance_large() {
+        let wide = |offset: u32| (0..25_000).fold(Tree::new(0u32), |t, i| t.add_node(Tree::new(i % 5_000 + offset)));
+        let (left_prof, right_prof) = (pqgram_profile(wide(0), 2, 3, true), pqgram_profile(wide(2_500), 2, 3, true));
+        let left = CountedProfile::from_profile(&left_prof);
+        let right = CountedProfile::from_profile(&right_prof);
+        assert!(left.len() > 50_000 && right.len() > 50_000);
+        let distance = counted_pqgram_distance(&left, &right);
+        assert!(distance > 0. && distance < 1.);
+        assert_eq!(distance, pqgram_distance(&left_prof, &right_prof));
+    }
+}
@@ -1,6 +1,13 @@
-use pqgrams::{Node, ValidGramElement, LabelledTree};
+use std::error;
+use std::fmt;
+use std::iter::FromIterator;
+#[cfg(feature = "rand")]
+use rand::Rng;
+use bdeque::BDeque;
+use pqgrams::{Node, ValidGramElement, LabelledTree, Profile, _profile_node, pqgram_distance, sort_profile};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
 pub struct Tree<T: ValidGramElement> {
     pub label: T,
     pub children: Box<Vec<Tree<T>>>,
@@ -16,22 +23,95 @@ impl ValidGramElement for u32 {}
 impl ValidGramElement for i64 {}
 impl ValidGramElement for u64 {}
 
+/// Labels with a deterministic, platform-independent byte encoding. Implement this to
+/// use Tree::canonical_bytes and Tree::profile_key with a custom label type; the
+/// encoding must be unambiguous, e.g. by length-prefixing variable-length data.
+pub trait CanonicalLabel {
+    fn write_canonical(&self, out: &mut Vec<u8>);
+}
+
+impl CanonicalLabel for String {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.len() as u64).to_le_bytes());
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+macro_rules! canonical_int_label {
+    ($($t:ty),*) => {$(
+        impl CanonicalLabel for $t {
+            fn write_canonical(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+    )*}
+}
+
+canonical_int_label!(i8, u8, i16, u16, i32, u32, i64, u64);
 
 impl<T: ValidGramElement> LabelledTree<T> for Tree<T> {
     fn label(&self) -> Node<T> {
-        return Node::Label(self.label.to_owned())
+        Node::Label(self.label.to_owned())
     }
     fn children(&self) -> Vec<&Tree<T>> {
         self.children.iter().map(|c| c as &Tree<T>).collect()
     }
 }
 
+/// Dropped iteratively, so that very deep trees don't overflow the stack on the way
+/// out either. Having a Drop impl means a Tree can't be destructured by value; the
+/// derived Clone, PartialEq and Debug still recurse.
+impl<T: ValidGramElement> Drop for Tree<T> {
+    fn drop(&mut self) {
+        let mut pending: Vec<Tree<T>> = self.children.drain(..).collect();
+        while let Some(mut tree) = pending.pop() {
+            pending.extend(tree.children.drain(..));
+        }
+    }
+}
+
 impl<T: ValidGramElement> Tree<T> {
     pub fn new(label: T) -> Tree<T> {
-        Tree{label: label, children: Box::new(vec![])}
+        Tree{label, children: Box::new(vec![])}
     }
 
-    // TODO: Add random tree feature, assists testing.
+    /// Generate a random tree, e.g. to fuzz a LabelledTree implementation against
+    /// Tree. The tree is grown level by level: each node above params.max_depth gets
+    /// up to params.max_children children, until params.max_nodes nodes have been
+    /// made. There is always at least the root. Labels come from params.label, and
+    /// the same seeded rng always gives the same tree.
+    #[cfg(feature = "rand")]
+    pub fn random<R, F>(rng: &mut R, params: &RandomTreeParams<F>) -> Tree<T>
+        where R: Rng, F: Fn(&mut R) -> T
+    {
+        let mut nodes = vec![Some(Tree::new((params.label)(rng)))];
+        // The parent and depth of each node, in the order they were made.
+        let mut shape = vec![(0, 0)];
+        let mut i = 0;
+        while i < nodes.len() {
+            let depth = shape[i].1;
+            if depth < params.max_depth {
+                let fan_out = rng.gen_range(0..=params.max_children);
+                for _ in 0..fan_out {
+                    if nodes.len() >= params.max_nodes { break }
+                    nodes.push(Some(Tree::new((params.label)(rng))));
+                    shape.push((i, depth + 1));
+                }
+            }
+            i += 1;
+        }
+        // Children are made after their parents, so attaching them last first leaves
+        // every node complete before it is attached; each node's children then need
+        // reversing back into order.
+        for i in (1..nodes.len()).rev() {
+            let mut child = nodes[i].take().expect("Each node is attached once");
+            child.children.reverse();
+            nodes[shape[i].0].as_mut().expect("Parents are attached after children").children.push(child);
+        }
+        let mut root = nodes[0].take().expect("The root is never attached");
+        root.children.reverse();
+        root
+    }
 
     /// Builder-pattern tree building helper. This returns self,
     /// so you can use it with Tree::new() to build nested trees
@@ -42,10 +122,204 @@ impl<T: ValidGramElement> Tree<T> {
     }
 }
 
+impl<T: ValidGramElement + CanonicalLabel> Tree<T> {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        // Walked with an explicit stack, like to_bracket, so that deep trees can be
+        // keyed as well as profiled.
+        let mut pending = vec![self];
+        while let Some(tree) = pending.pop() {
+            tree.label.write_canonical(out);
+            out.extend_from_slice(&(tree.children.len() as u64).to_le_bytes());
+            pending.extend(tree.children.iter().rev());
+        }
+    }
+
+    /// A deterministic byte encoding of the tree (labels and shape, in preorder),
+    /// suitable as a cache or database key. Equal trees give equal bytes.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_canonical(&mut out);
+        out
+    }
+
+    /// A key for the tree's profile under a given configuration: the canonical bytes
+    /// prefixed with p, q and the filler value, so profiles built differently from
+    /// the same tree never share a key.
+    pub fn profile_key(&self, p: usize, q: usize, alt_filler_value: Option<&T>) -> Vec<u8> {
+        let mut out = b"pqgrams-profile-v1".to_vec();
+        out.extend_from_slice(&(p as u64).to_le_bytes());
+        out.extend_from_slice(&(q as u64).to_le_bytes());
+        match alt_filler_value {
+            Some(filler) => {
+                out.push(1);
+                filler.write_canonical(&mut out);
+            },
+            None => out.push(0),
+        }
+        self.write_canonical(&mut out);
+        out
+    }
+}
+
 impl Tree<String> {
     /// Minor convenience to save the use of to_string on everything
     /// when building trees.
     pub fn new_str(label: &str) -> Tree<String> {
         Tree{label: label.to_string(), children: Box::new(vec![])}
     }
+
+    /// Parse a tree in the bracket notation used by APTED and tree edit distance
+    /// datasets, e.g. `{a{b}{c}}`. A backslash escapes the next character, so labels
+    /// may contain braces as `\{` and `\}`; labels may also be empty, as in `{{x}}`.
+    /// Whitespace around the tree and after a closing brace, as in `{a{b} {c}}`, is
+    /// ignored; anywhere else it is part of a label, so `{a b}` is labelled "a b".
+    pub fn from_bracket(s: &str) -> Result<Tree<String>, ParseError> {
+        let err = |position, message: &str| Err(ParseError{position, message: message.to_string()});
+        let mut chars = s.char_indices().skip_while(|&(_, c)| c.is_whitespace()).peekable();
+        // Nodes whose closing brace has not been seen yet, innermost last.
+        let mut open: Vec<Tree<String>> = Vec::new();
+        loop {
+            match chars.next() {
+                Some((_, '{')) => {
+                    let mut label = String::new();
+                    while let Some(&(i, c)) = chars.peek() {
+                        match c {
+                            '{' | '}' => break,
+                            '\\' => {
+                                chars.next();
+                                match chars.next() {
+                                    Some((_, escaped)) => label.push(escaped),
+                                    None => return err(i, "unterminated escape"),
+                                }
+                            },
+                            _ => { label.push(c); chars.next(); },
+                        }
+                    }
+                    open.push(Tree::new(label));
+                },
+                Some((i, '}')) => {
+                    let node = match open.pop() {
+                        Some(node) => node,
+                        None => return err(i, "unbalanced '}'"),
+                    };
+                    match open.last_mut() {
+                        Some(parent) => {
+                            parent.children.push(node);
+                            while chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) {
+                                chars.next();
+                            }
+                        },
+                        None => return match chars.find(|&(_, c)| !c.is_whitespace()) {
+                            Some((i, _)) => err(i, "trailing characters"),
+                            None => Ok(node),
+                        },
+                    }
+                },
+                Some((i, _)) => return err(i, if open.is_empty() { "expected '{'" } else { "expected '{' or '}'" }),
+                None => return err(s.len(), if open.is_empty() { "empty input" } else { "unclosed '{'" }),
+            }
+        }
+    }
+
+    /// Write the tree in bracket notation, escaping braces and backslashes in labels,
+    /// so that from_bracket reads it back as an equal tree.
+    pub fn to_bracket(&self) -> String {
+        let mut out = String::new();
+        // None stands for the closing brace of a node whose children are pending.
+        let mut pending = vec![Some(self)];
+        while let Some(item) = pending.pop() {
+            match item {
+                Some(tree) => {
+                    out.push('{');
+                    for c in tree.label.chars() {
+                        if c == '{' || c == '}' || c == '\\' { out.push('\\') }
+                        out.push(c);
+                    }
+                    pending.push(None);
+                    pending.extend(tree.children.iter().rev().map(Some));
+                },
+                None => out.push('}'),
+            }
+        }
+        out
+    }
+}
+
+/// Limits and a label sampler for Tree::random.
+#[cfg(feature = "rand")]
+#[derive(Clone,Debug)]
+pub struct RandomTreeParams<F> {
+    /// The deepest a node may be, the root being at depth 0.
+    pub max_depth: usize,
+    /// The most children any node may have.
+    pub max_children: usize,
+    /// The most nodes in the tree, root included.
+    pub max_nodes: usize,
+    /// Draws each node's label.
+    pub label: F,
+}
+
+/// An error reading bracket notation, with the byte offset at which it occurred.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid bracket notation at byte {}: {}", self.position, self.message)
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// A multi-rooted collection of trees. Forests are profiled as if their trees were
+/// the children of a virtual root whose label is Node::Filler, so no synthetic root
+/// label has to be invented. An empty forest has an empty profile, like an empty tree.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
+pub struct Forest<T: ValidGramElement>(pub Vec<Tree<T>>);
+
+impl<T: ValidGramElement> Forest<T> {
+    pub fn new() -> Forest<T> {
+        Forest(vec![])
+    }
+
+    /// Builder-pattern helper, as Tree::add_node.
+    pub fn add_tree(mut self, tree: Tree<T>) -> Forest<T> {
+        self.0.push(tree);
+        self
+    }
+
+    /// Build the PQGram profile of the forest, as pqgram_profile does for a tree.
+    pub fn profile(&self, p: usize, q: usize, sort: bool) -> Profile<T> {
+        assert!(p > 0 && q > 0, "p and q must both be at least 1, got p = {}, q = {}", p, q);
+        if self.0.is_empty() {
+            return Profile::from_parts(vec![], p, q, true).with_node_count(0)
+        }
+        let mut ancestors = BDeque::<Node<T>>::new(p);
+        ancestors.fill_with(Node::Filler);
+        let (mut prof, nodes) = _profile_node(Node::Filler, self.0.iter().collect(), q, &mut ancestors);
+        if sort { sort_profile(&mut prof) }
+        // The virtual root is not one of the forest's nodes.
+        Profile::from_profiler(prof, p, q, sort, nodes - 1)
+    }
+
+    /// PQGram distance between two forests, profiled with the same p and q.
+    pub fn distance(&self, other: &Forest<T>, p: usize, q: usize) -> f64 {
+        pqgram_distance(&self.profile(p, q, true), &other.profile(p, q, true))
+    }
+}
+
+impl<T: ValidGramElement> Default for Forest<T> {
+    fn default() -> Forest<T> {
+        Forest::new()
+    }
+}
+
+impl<T: ValidGramElement> FromIterator<Tree<T>> for Forest<T> {
+    fn from_iter<I: IntoIterator<Item=Tree<T>>>(iter: I) -> Forest<T> {
+        Forest(iter.into_iter().collect())
+    }
 }
@@ -0,0 +1,112 @@
+use default_tree::CanonicalLabel;
+use pqgrams::{Node, PQGram, Profile, ValidGramElement, map_profile_labels};
+
+/// FNV-1a, which unlike std's hashers is stable across Rust releases and platforms.
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub(crate) fn new() -> Fnv1a {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn write_canonical_nodes<T: CanonicalLabel + ValidGramElement>(nodes: &[Node<T>], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(nodes.len() as u64).to_le_bytes());
+    for node in nodes {
+        match *node {
+            Node::Filler => out.push(0),
+            Node::Label(ref l) => { out.push(1); l.write_canonical(out) },
+        }
+    }
+}
+
+/// A stable 64-bit digest of a profile's grams, the same whatever order they were
+/// generated in and on every platform. Comparing digests answers "has the structure
+/// changed at all?" without a distance computation; equal profiles always have
+/// equal digests, and different ones almost never do.
+pub fn profile_digest<T: CanonicalLabel + ValidGramElement>(profile: &[PQGram<T>]) -> u64 {
+    let mut grams: Vec<&PQGram<T>> = profile.iter().collect();
+    grams.sort_unstable();
+    let mut hasher = Fnv1a::new();
+    let mut buf = Vec::new();
+    for gram in grams {
+        buf.clear();
+        write_canonical_nodes(gram.ancestors(), &mut buf);
+        write_canonical_nodes(gram.siblings(), &mut buf);
+        hasher.write(&buf);
+    }
+    hasher.finish()
+}
+
+impl<T: CanonicalLabel + ValidGramElement> Profile<T> {
+    /// As profile_digest.
+    pub fn digest(&self) -> u64 {
+        profile_digest(self)
+    }
+
+    /// Replace every label with a pseudonym hashed from the salt and the label, so a
+    /// profile can be shared without its content. Equal labels get equal pseudonyms
+    /// under the same salt, so distances between profiles anonymized with one salt
+    /// are unchanged, barring 64-bit collisions. Use one secret salt per corpus. The
+    /// hash is FNV-1a, which hides labels from casual reading but is not
+    /// cryptographic; where labels must resist guessing, map them through a keyed
+    /// cryptographic hash with map_profile_labels instead.
+    pub fn anonymize(&self, salt: &[u8]) -> Profile<u64> {
+        let pseudonym = |label: &T| {
+            let mut bytes = Vec::new();
+            label.write_canonical(&mut bytes);
+            let mut hasher = Fnv1a::new();
+            hasher.write(&(salt.len() as u64).to_le_bytes());
+            hasher.write(salt);
+            hasher.write(&bytes);
+            hasher.finish()
+        };
+        let grams = map_profile_labels(self, pseudonym, true);
+        Profile::from_parts(grams, self.p(), self.q(), true).with_node_count_opt(self.node_count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use default_tree::Tree;
+    use pqgrams::{pqgram_profile, pqgram_distance};
+    use tests::{build_known_tree_1, build_known_tree_2};
+    use super::profile_digest;
+
+    #[test]
+    fn test_profile_digest() {
+        let unsorted = pqgram_profile(build_known_tree_1(), 2, 3, false);
+        let sorted = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        assert_eq!(profile_digest(&unsorted), profile_digest(&sorted));
+        assert!(profile_digest(&sorted) != profile_digest(&pqgram_profile(build_known_tree_2(), 2, 3, true)));
+        // A "*" label is not a filler.
+        let star = pqgram_profile(Tree::new_str("a").add_node(Tree::new_str("*")), 2, 3, true);
+        let leaf = pqgram_profile(Tree::new_str("a"), 2, 3, true);
+        assert!(profile_digest(&star) != profile_digest(&leaf));
+        assert_eq!(profile_digest::<String>(&[]), 0xcbf2_9ce4_8422_2325);
+    }
+
+    #[test]
+    fn test_anonymize_profile() {
+        let prof1 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof2 = pqgram_profile(build_known_tree_2(), 2, 3, true);
+        let anon1 = prof1.anonymize(b"salt");
+        let anon2 = prof2.anonymize(b"salt");
+        assert_eq!(pqgram_distance(&anon1, &anon2),
+                   pqgram_distance(&prof1, &prof2));
+        assert_eq!(anon1, prof1.anonymize(b"salt"));
+        assert!(anon1 != prof1.anonymize(b"pepper"));
+        assert_eq!((anon1.p(), anon1.q(), anon1.len()), (2, 3, 13));
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use pqgrams::{Profile, ValidGramElement, pqgram_distance_normalized};
+
+/// Classes of labels to be treated as equal when comparing, e.g. `h1` to `h6`, or
+/// `b` and `strong`, so semantically equivalent markup doesn't inflate distances.
+/// Every label in a class compares as the class's representative.
+#[derive(Clone, Debug)]
+pub struct LabelEquivalence<L: ValidGramElement> {
+    representatives: BTreeMap<L, L>,
+}
+
+impl<L: ValidGramElement> Default for LabelEquivalence<L> {
+    fn default() -> LabelEquivalence<L> {
+        LabelEquivalence{representatives: BTreeMap::new()}
+    }
+}
+
+impl<L: ValidGramElement> LabelEquivalence<L> {
+    pub fn new() -> LabelEquivalence<L> {
+        LabelEquivalence::default()
+    }
+
+    /// Builder-pattern helper registering a class of equivalent labels. Classes that
+    /// share a label are merged, so equivalence stays transitive.
+    pub fn add_class(mut self, members: &[L]) -> LabelEquivalence<L> {
+        let existing: Vec<L> = members.iter().filter_map(|m| self.representatives.get(m).cloned()).collect();
+        let representative = match existing.first().or_else(|| members.first()) {
+            Some(r) => r.clone(),
+            None => return self,
+        };
+        for rep in self.representatives.values_mut() {
+            if existing.contains(rep) {
+                *rep = representative.clone();
+            }
+        }
+        for member in members {
+            self.representatives.insert(member.clone(), representative.clone());
+        }
+        self
+    }
+
+    /// The label l compares as: its class's representative, or l itself.
+    pub fn canonical(&self, label: &L) -> L {
+        self.representatives.get(label).unwrap_or(label).clone()
+    }
+
+    /// pqgram_distance between two profiles with equivalent labels treated as equal.
+    pub fn distance(&self, left: &Profile<L>, right: &Profile<L>) -> f64 {
+        pqgram_distance_normalized(left, right, |l| self.canonical(l))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use default_tree::Tree;
+    use pqgrams::{pqgram_profile, pqgram_distance};
+    use super::LabelEquivalence;
+
+    fn labels(ls: &[&str]) -> Vec<String> {
+        ls.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_label_equivalence() {
+        let bold = pqgram_profile(Tree::new_str("p").add_node(Tree::new_str("b")), 2, 2, true);
+        let strong = pqgram_profile(Tree::new_str("p").add_node(Tree::new_str("strong")), 2, 2, true);
+        let italic = pqgram_profile(Tree::new_str("p").add_node(Tree::new_str("i")), 2, 2, true);
+        let eq = LabelEquivalence::new().add_class(&labels(&["b", "strong"]));
+        assert_eq!(pqgram_distance(&bold, &strong), 1.);
+        assert_eq!(eq.distance(&bold, &strong), 0.);
+        assert_eq!(eq.distance(&bold, &italic), 1.);
+        // Overlapping classes merge.
+        let eq = eq.add_class(&labels(&["i", "em"])).add_class(&labels(&["em", "strong"]));
+        assert_eq!(eq.canonical(&"i".to_string()), eq.canonical(&"b".to_string()));
+        assert_eq!(eq.distance(&bold, &italic), 0.);
+        assert_eq!(eq.canonical(&"u".to_string()), "u");
+    }
+}
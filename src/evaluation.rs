@@ -0,0 +1,88 @@
+use pqgrams::{pqgram_profile_ref, pqgram_distance, LabelledTree, ValidGramElement};
+
+/// How well one (p, q, threshold) configuration separated duplicates from the rest,
+/// calling a pair a duplicate when its distance is at most the threshold.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Evaluation {
+    pub p: usize,
+    pub q: usize,
+    pub threshold: f64,
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+}
+
+impl Evaluation {
+    fn from_counts(p: usize, q: usize, threshold: f64, true_pos: usize, false_pos: usize, false_neg: usize) -> Evaluation {
+        let ratio = |n: usize, d: usize| if d == 0 { 0. } else { n as f64 / d as f64 };
+        let precision = ratio(true_pos, true_pos + false_pos);
+        let recall = ratio(true_pos, true_pos + false_neg);
+        let f1 = if precision + recall == 0. { 0. } else { 2. * precision * recall / (precision + recall) };
+        Evaluation{p, q, threshold, precision, recall, f1}
+    }
+}
+
+/// Score every combination of (p, q) and threshold against pairs of trees labelled
+/// true for duplicates, so the settings can be tuned on your own data. Each pair is
+/// profiled once per (p, q). Results come in the order of params, then thresholds;
+/// precision and recall are 0 where undefined.
+pub fn evaluate_thresholds<L, T>(pairs: &[(T, T, bool)], params: &[(usize, usize)], thresholds: &[f64]) -> Vec<Evaluation>
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    let mut results = Vec::with_capacity(params.len() * thresholds.len());
+    for &(p, q) in params {
+        let distances: Vec<(f64, bool)> = pairs.iter().map(|(left, right, duplicate)| {
+            let left = pqgram_profile_ref(left, p, q, true);
+            let right = pqgram_profile_ref(right, p, q, true);
+            (pqgram_distance(&left, &right), *duplicate)
+        }).collect();
+        for &threshold in thresholds {
+            let (mut true_pos, mut false_pos, mut false_neg) = (0, 0, 0);
+            for &(distance, duplicate) in &distances {
+                match (distance <= threshold, duplicate) {
+                    (true, true) => true_pos += 1,
+                    (true, false) => false_pos += 1,
+                    (false, true) => false_neg += 1,
+                    (false, false) => {},
+                }
+            }
+            results.push(Evaluation::from_counts(p, q, threshold, true_pos, false_pos, false_neg));
+        }
+    }
+    results
+}
+
+/// The configuration with the highest F1, preferring the earliest on ties.
+pub fn best_evaluation(results: &[Evaluation]) -> Option<&Evaluation> {
+    results.iter().fold(None, |best: Option<&Evaluation>, e| match best {
+        Some(b) if b.f1 >= e.f1 => Some(b),
+        _ => Some(e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use default_tree::Tree;
+    use super::{evaluate_thresholds, best_evaluation};
+
+    fn list(item: &str, n: usize) -> Tree<String> {
+        (0..n).fold(Tree::new_str("ul"), |t, _| t.add_node(Tree::new_str(item)))
+    }
+
+    #[test]
+    fn test_evaluate_thresholds() {
+        let pairs = vec![
+            (list("li", 3), list("li", 3), true),
+            (list("li", 3), list("li", 4), true),
+            (list("li", 3), list("td", 3), false),
+        ];
+        let results = evaluate_thresholds(&pairs, &[(2, 3), (1, 1)], &[0., 0.5, 1.]);
+        assert_eq!(results.len(), 6);
+        assert_eq!((results[0].precision, results[0].recall), (1., 0.5));
+        assert_eq!((results[1].precision, results[1].recall, results[1].f1), (1., 1., 1.));
+        assert_eq!((results[2].precision, results[2].recall), (2. / 3., 1.));
+        let best = best_evaluation(&results).unwrap();
+        assert_eq!((best.p, best.q, best.threshold), (2, 3, 0.5));
+        assert!(best_evaluation(&[]).is_none());
+    }
+}
@@ -0,0 +1,151 @@
+use std::{fs, io, process};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use cache::ProfileCache;
+use digest::Fnv1a;
+use pqgrams::{Node, PQGram, Profile};
+
+/// A ProfileCache keeping one file per profile in a directory, so batch jobs that
+/// restart don't re-profile unchanged documents. Profiles are stored one gram per
+/// line, fillers tagged apart from labels so that any label, "*" included, reads
+/// back as itself, along with the full key, which is checked on read to rule out
+/// hash collisions, and the node count, if known. I/O errors and malformed files are treated as cache misses.
+#[derive(Clone, Debug)]
+pub struct FileProfileCache {
+    dir: PathBuf,
+}
+
+const FILE_CACHE_HEADER: &str = "pqgrams-cache-v3";
+
+/// Numbers this process's temporary files, so concurrent writers never share one.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl FileProfileCache {
+    /// Use dir for cache files, creating it if needed.
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<FileProfileCache> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(FileProfileCache{dir: dir.as_ref().to_path_buf()})
+    }
+
+    fn stem_for(&self, key: &[u8]) -> String {
+        let mut hasher = Fnv1a::new();
+        hasher.write(key);
+        format!("{:016x}", hasher.finish())
+    }
+
+    fn path_for(&self, key: &[u8]) -> PathBuf {
+        self.dir.join(format!("{}.profile", self.stem_for(key)))
+    }
+
+    fn read(&self, key: &[u8]) -> Option<Profile<String>> {
+        let contents = fs::read_to_string(self.path_for(key)).ok()?;
+        let mut lines = contents.splitn(4, '\n');
+        if lines.next()? != FILE_CACHE_HEADER || lines.next()? != hex(key) {
+            return None
+        }
+        let mut params = lines.next()?.split(' ');
+        let p = params.next()?.parse().ok()?;
+        let q = params.next()?.parse().ok()?;
+        if p == 0 || q == 0 { return None }
+        let node_count = match params.next()? {
+            "-" => None,
+            n => Some(n.parse().ok()?),
+        };
+        let grams = lines.next()?.lines().map(|line| read_gram(line, p, q)).collect::<Option<Vec<_>>>()?;
+        Some(Profile::new(grams, p, q).with_node_count_opt(node_count))
+    }
+
+    fn write(&self, key: &[u8], profile: &Profile<String>) -> io::Result<()> {
+        let node_count = profile.node_count().map_or("-".to_string(), |n| n.to_string());
+        let mut contents = format!("{}\n{}\n{} {} {}\n", FILE_CACHE_HEADER, hex(key), profile.p(), profile.q(), node_count);
+        for gram in profile {
+            write_gram(&mut contents, gram);
+        }
+        // Write then rename, so concurrent readers never see a partial file. The
+        // temporary name is unique to this process and write, so two writers of
+        // the same key never interleave in one file.
+        let tmp = self.dir.join(format!("{}.{}.{}.tmp", self.stem_for(key), process::id(),
+                                        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let path = self.path_for(key);
+        fs::write(&tmp, contents)?;
+        fs::rename(tmp, path)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) { return None }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// A gram as a line of space-separated nodes: "-" for a filler, or "+" and the
+/// label's UTF-8 bytes in hex.
+fn write_gram(out: &mut String, gram: &PQGram<String>) {
+    for (i, node) in gram.ancestors().iter().chain(gram.siblings()).enumerate() {
+        if i > 0 { out.push(' ') }
+        match *node {
+            Node::Filler => out.push('-'),
+            Node::Label(ref label) => { out.push('+'); out.push_str(&hex(label.as_bytes())) },
+        }
+    }
+    out.push('\n');
+}
+
+fn read_gram(line: &str, p: usize, q: usize) -> Option<PQGram<String>> {
+    let nodes = line.split(' ').map(|token| match token.get(..1)? {
+        "-" if token.len() == 1 => Some(Node::Filler),
+        "+" => String::from_utf8(unhex(&token[1..])?).ok().map(Node::Label),
+        _ => None,
+    }).collect::<Option<Vec<_>>>()?;
+    if nodes.len() != p + q { return None }
+    let mut ancestors = nodes;
+    let siblings = ancestors.split_off(p);
+    Some(PQGram::new(ancestors, siblings))
+}
+
+impl ProfileCache<String> for FileProfileCache {
+    fn get(&mut self, key: &[u8]) -> Option<Profile<String>> {
+        self.read(key)
+    }
+
+    fn put(&mut self, key: Vec<u8>, profile: Profile<String>) {
+        let _ = self.write(&key, &profile);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use cache::{ProfileCache, cached_profile};
+    use default_tree::Tree;
+    use pqgrams::pqgram_profile;
+    use super::FileProfileCache;
+
+    #[test]
+    fn test_file_profile_cache() {
+        let dir = env::temp_dir().join(format!("pqgrams-cache-test-{}", process::id()));
+        let tree = Tree::new_str("a").add_node(Tree::new_str("b")).add_node(Tree::new_str("c"));
+        {
+            let mut cache = FileProfileCache::new(&dir).unwrap();
+            assert!(cache.get(&tree.profile_key(2, 3, None)).is_none());
+            cached_profile(&mut cache, &tree, 2, 3);
+        }
+        // A fresh cache over the same directory sees the stored profile.
+        let mut cache = FileProfileCache::new(&dir).unwrap();
+        assert_eq!(cache.get(&tree.profile_key(2, 3, None)), Some(pqgram_profile(tree.clone(), 2, 3, true)));
+        assert_eq!(cache.get(&tree.profile_key(2, 3, None)).unwrap().node_count(), Some(3));
+        assert!(cache.get(&tree.profile_key(3, 3, None)).is_none());
+        // A literal "*" label, an empty one and a multi-byte one come back as labels.
+        let awkward = Tree::new_str("a").add_node(Tree::new_str("*")).add_node(Tree::new_str("")).add_node(Tree::new_str("é *"));
+        cached_profile(&mut cache, &awkward, 2, 3);
+        assert_eq!(cache.get(&awkward.profile_key(2, 3, None)), Some(pqgram_profile(awkward.clone(), 2, 3, true)));
+        // Only finished profiles are left behind, under a neutral extension.
+        let names: Vec<String> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.iter().all(|name| name.ends_with(".profile")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,21 +1,69 @@
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "rand")]
+extern crate rand;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(all(test, feature = "serde"))]
+extern crate bincode;
+
 mod bdeque;
 mod pqgrams;
 mod default_tree;
-pub use default_tree::Tree;
-pub use pqgrams::{pqgram_distance, ValidGramElement, LabelledTree, PQGram, Node, pqgram_profile, flatten_profile, pqgram_distance_with_fn};
+mod digest;
+mod analysis;
+mod chunks;
+mod node_class;
+mod pygram;
+mod cache;
+mod equivalence;
+mod snapshot;
+mod counted;
+mod evaluation;
+#[cfg(feature = "disk-cache")]
+mod file_cache;
+#[cfg(feature = "rayon")]
+mod parallel;
+pub use default_tree::{Tree, Forest, CanonicalLabel, ParseError};
+pub use digest::profile_digest;
+pub use pqgrams::{pqgram_distance, ValidGramElement, LabelledTree, PQGram, Profile, Node, pqgram_profile, flatten_profile, pqgram_distance_with_fn, pqgram_profile_checked, ProfileError, group_by_anchor, pqgram_distance_by_anchor, pqgram_distance_ensemble, GramDistanceFn, default_gram_edit_distance, concat_profiles, pqgram_profile_opt, pqgram_distance_to_profile, pqgram_profile_ref, sort_profile, pqgram_distance_with_budget, Budget, BudgetedDistance, DEADLINE_CHECK_INTERVAL, map_profile_labels, pqgram_distance_normalized, pqgram_profile_each, pqgram_profile_distinct, pqgram_profile_depth_limited, pqgram_profile_acyclic, estimate_profile_len, ExactDistance, pqgram_distance_exact, pqgram_distance_size_aware};
+#[cfg(feature = "rand")]
+pub use default_tree::RandomTreeParams;
+#[cfg(feature = "rayon")]
+pub use pqgrams::par_sort_profile;
+#[cfg(feature = "rayon")]
+pub use parallel::{par_pqgram_profile, pqgram_profiles_par, par_pairwise_distances};
+pub use analysis::{discriminative_grams, NearestCentroid, explain, Explanation, VersionedTree, VersionDrift, profile_histogram, histogram_to_csv, histogram_to_json, corpus_divergence, predict_label, containment};
+pub use chunks::{chunk_tree, match_chunks};
+pub use node_class::{NodeClass, ClassWeights, class_weighted_gram_distance};
+pub use pygram::{unflatten_profile, profile_to_pygram_json, profile_from_pygram_json, PyGramError, PYGRAM_FILLER};
+pub use equivalence::LabelEquivalence;
+pub use counted::{CountedProfile, counted_pqgram_distance};
+pub use snapshot::{check_profile_snapshot, assert_profile_snapshot, SnapshotOutcome, UPDATE_SNAPSHOTS_VAR};
+pub use evaluation::{Evaluation, evaluate_thresholds, best_evaluation};
+pub use cache::{ProfileCache, LruProfileCache, cached_profile, cached_distance};
+#[cfg(feature = "disk-cache")]
+pub use file_cache::FileProfileCache;
 
 
 #[cfg(test)]
 mod tests {
-    use super::default_tree::Tree;
-    use super::{pqgram_distance, pqgram_profile, flatten_profile};
+    use std::cell::{Cell, RefCell};
+    use std::cmp;
+    use super::bdeque::BDeque;
+    use std::time::Instant;
+    use super::default_tree::{Tree, Forest};
+    use super::{Profile, CountedProfile, counted_pqgram_distance, GramDistanceFn, pqgram_distance_with_fn, map_profile_labels, pqgram_distance_normalized, pqgram_distance_with_budget, Budget, BudgetedDistance, sort_profile, pqgram_distance, pqgram_profile, flatten_profile, pqgram_profile_checked, ProfileError, group_by_anchor, Node, pqgram_distance_by_anchor, pqgram_distance_ensemble, default_gram_edit_distance, PQGram, concat_profiles, pqgram_profile_opt, pqgram_distance_to_profile, pqgram_profile_each, pqgram_profile_distinct, pqgram_profile_depth_limited, pqgram_profile_acyclic, LabelledTree, estimate_profile_len, ExactDistance, pqgram_distance_exact, pqgram_profile_ref, pqgram_distance_size_aware};
 
     // Utility function
     fn f64_round_2dp(n: f64) -> f64 {
         (n * 100.).round() / 100.
     }
 
-    fn build_known_tree_1() -> Tree<String> {
+    pub(crate) fn build_known_tree_1() -> Tree<String> {
         Tree::new_str("a")
                 .add_node(Tree::new_str("a")
                             .add_node(Tree::new_str("e"))
@@ -42,7 +90,7 @@ mod tests {
             ].iter().map(|v| v.iter().map(|s| s.to_string()).collect()).collect()
     }
 
-    fn build_known_tree_2() -> Tree<String> {
+    pub(crate) fn build_known_tree_2() -> Tree<String> {
         Tree::new_str("a")
                 .add_node(Tree::new_str("a")
                             .add_node(Tree::new_str("e"))
@@ -65,12 +113,106 @@ mod tests {
         let prof1 = pqgram_profile(tree_1, 2, 3, false);
         let prof2 = pqgram_profile(tree_2, 2, 3, false);
         let prof3 = pqgram_profile(tree_3, 2, 3, false);
-        let dist12 = pqgram_distance::<String, Tree<String>>(&prof1, &prof2, None);
-        let dist13 = pqgram_distance::<String, Tree<String>>(&prof1, &prof3, None);
+        let dist12 = pqgram_distance(&prof1, &prof2);
+        let dist13 = pqgram_distance(&prof1, &prof3);
         assert_eq!(f64_round_2dp(dist12), 0.);    // Same
         assert_eq!(f64_round_2dp(dist13), 0.31);  // Differ by 0.31
     }
 
+    #[test]
+    fn test_profile_parameters() {
+        let mut prof = pqgram_profile(build_known_tree_1(), 2, 3, false);
+        assert_eq!((prof.p(), prof.q(), prof.is_sorted()), (2, 3, false));
+        assert_eq!(flatten_profile(&prof, "*".to_string()).len(), 13);
+        prof.sort_in_place();
+        assert_eq!(prof, pqgram_profile(build_known_tree_1(), 2, 3, true));
+        let stored = Profile::new(prof.clone().into_grams(), 2, 3);
+        assert!(stored.is_sorted());
+        assert_eq!(prof.group_by_anchor().len(), 4);
+    }
+
+    #[test]
+    fn test_pqgram_distance_unsorted() {
+        let sorted_1 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let unsorted_2 = pqgram_profile(build_known_tree_2(), 2, 3, false);
+        let sorted_2 = pqgram_profile(build_known_tree_2(), 2, 3, true);
+        assert!(!unsorted_2.is_sorted());
+        assert_eq!(pqgram_distance(&sorted_1, &unsorted_2),
+                   pqgram_distance(&sorted_1, &sorted_2));
+    }
+
+    #[test]
+    fn test_slice_taking_distances_sort_profiles() {
+        let unsorted = pqgram_profile(build_known_tree_1(), 2, 3, false);
+        let sorted = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        assert_eq!(pqgram_distance_by_anchor(&unsorted, &sorted, |_| true), 0.);
+        assert_eq!(pqgram_distance_exact(&unsorted, &sorted).to_f64(), 0.);
+        assert_eq!(pqgram_distance_ensemble(&unsorted, &sorted, None, vec![(1., Box::new(default_gram_edit_distance))]).0, 0.);
+        assert_eq!(pqgram_distance_with_budget(&unsorted, &sorted, None, default_gram_edit_distance, Budget::default()),
+                   BudgetedDistance::Exact(0.));
+        assert_eq!(pqgram_distance_normalized(&unsorted, &sorted, |l| l.clone()), 0.);
+        assert_eq!(super::explain(&unsorted, &sorted).distance, 0.);
+    }
+
+    #[test]
+    #[should_panic(expected = "different parameters")]
+    fn test_by_anchor_mismatched_parameters() {
+        let prof_23 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof_24 = pqgram_profile(build_known_tree_1(), 2, 4, true);
+        pqgram_distance_by_anchor(&prof_23, &prof_24, |_| true);
+    }
+
+    #[test]
+    #[should_panic(expected = "different parameters")]
+    fn test_exact_mismatched_parameters() {
+        let prof_23 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof_24 = pqgram_profile(build_known_tree_1(), 2, 4, true);
+        pqgram_distance_exact(&prof_23, &prof_24);
+    }
+
+    #[test]
+    #[should_panic(expected = "different parameters: p = 2, q = 3 and p = 2, q = 4")]
+    fn test_pqgram_distance_mismatched_parameters() {
+        let prof_23 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof_24 = pqgram_profile(build_known_tree_1(), 2, 4, true);
+        pqgram_distance(&prof_23, &prof_24);
+    }
+
+    #[test]
+    fn test_counted_pqgram_distance() {
+        let prof1 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof3 = pqgram_profile(build_known_tree_2(), 2, 3, false);
+        let (counted1, counted3) = (CountedProfile::from_profile(&prof1), CountedProfile::from_profile(&prof3));
+        assert_eq!(counted1.len(), 13);
+        assert!(counted1.iter().any(|(_, &n)| n == 2));
+        assert_eq!(counted_pqgram_distance(&counted1, &counted1), 0.);
+        assert_eq!(f64_round_2dp(counted_pqgram_distance(&counted1, &counted3)), 0.31);
+        assert_eq!(counted_pqgram_distance(&counted1, &counted3), pqgram_distance(&prof1, &prof3));
+    }
+
+    #[test]
+    fn test_pqgram_distance_exact() {
+        let prof1 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof3 = pqgram_profile(build_known_tree_2(), 2, 3, true);
+        let exact = pqgram_distance_exact(&prof1, &prof3);
+        assert_eq!(exact.to_f64(), pqgram_distance(&prof1, &prof3));
+        assert_eq!(pqgram_distance_exact(&prof1, &prof1), ExactDistance::new(13, 26));
+        let empty = Profile::<String>::new(vec![], 2, 3);
+        assert_eq!(pqgram_distance_exact(&empty, &empty).to_f64(), 0.);
+        // 1/3 and 2/6 are the same distance, and both are below 1/2.
+        let third = ExactDistance::new(1, 3);
+        let sixth = ExactDistance::new(2, 6);
+        assert!(third < ExactDistance::new(1, 4));
+        assert_eq!(third.numerator() * sixth.denominator(), sixth.numerator() * third.denominator());
+        assert_eq!((sixth.shared(), sixth.total()), (2, 6));
+    }
+
+    #[test]
+    #[should_panic(expected = "more than half")]
+    fn test_exact_distance_rejects_impossible_counts() {
+        ExactDistance::new(2, 3);
+    }
+
     #[test]
     fn test_pqgram_distance_when_sorted() {
         let tree_1 = build_known_tree_1();
@@ -79,9 +221,699 @@ mod tests {
         let prof1 = pqgram_profile(tree_1, 2, 3, true);
         let prof2 = pqgram_profile(tree_2, 2, 3, true);
         let prof3 = pqgram_profile(tree_3, 2, 3, true);
-        let dist12 = pqgram_distance::<String, Tree<String>>(&prof1, &prof2, None);
-        let dist13 = pqgram_distance::<String, Tree<String>>(&prof1, &prof3, None);
+        let dist12 = pqgram_distance(&prof1, &prof2);
+        let dist13 = pqgram_distance(&prof1, &prof3);
         assert_eq!(f64_round_2dp(dist12), 0.);    // Same
         assert_eq!(f64_round_2dp(dist13), 0.31);  // Differ by 0.31
     }
+
+    #[test]
+    fn test_pqgram_profile_checked() {
+        let prof = pqgram_profile_checked(&build_known_tree_1(), 2, 3, true, 13).unwrap();
+        assert_eq!(known_profile_1(), flatten_profile(&prof, "*".to_string()));
+        assert_eq!(pqgram_profile_checked(&build_known_tree_1(), 2, 3, true, 12),
+                   Err(ProfileError::TooManyGrams{grams: 13, max_grams: 12}));
+        assert_eq!(pqgram_profile_checked(&build_known_tree_1(), 2, usize::MAX, true, usize::MAX),
+                   Err(ProfileError::SizeOverflow));
+        assert_eq!(pqgram_profile_checked(&build_known_tree_1(), 0, 3, true, 100),
+                   Err(ProfileError::InvalidParameters{p: 0, q: 3}));
+        assert_eq!(pqgram_profile_checked(&build_known_tree_1(), 2, 0, true, 100),
+                   Err(ProfileError::InvalidParameters{p: 2, q: 0}));
+        // Sizing is iterative, so the guard itself survives chains too deep to recurse on.
+        let chain = deep_chain(200_000);
+        assert_eq!(pqgram_profile_checked(&chain, 2, 3, false, usize::MAX).unwrap().len(), 600_001);
+        assert_eq!(pqgram_profile_checked(&chain, 2, 3, false, 1000),
+                   Err(ProfileError::TooManyGrams{grams: 600_001, max_grams: 1000}));
+    }
+
+    #[test]
+    fn test_estimate_profile_len() {
+        assert_eq!(estimate_profile_len(&build_known_tree_1(), 3), 13);
+        assert_eq!(estimate_profile_len(&build_known_tree_2(), 2), pqgram_profile(build_known_tree_2(), 3, 2, false).len());
+        assert_eq!(estimate_profile_len(&Tree::new_str("a"), 3), 1);
+        assert_eq!(estimate_profile_len(&build_known_tree_1(), usize::MAX), usize::MAX);
+        assert_eq!(estimate_profile_len(&deep_chain(200_000), 3), 600_001);
+    }
+
+    #[test]
+    fn test_pqgram_profile_depth_limited() {
+        // build_known_tree_1 is three levels deep.
+        assert_eq!(pqgram_profile_depth_limited(&build_known_tree_1(), 2, 3, true, 3).unwrap(),
+                   pqgram_profile(build_known_tree_1(), 2, 3, true));
+        assert_eq!(pqgram_profile_depth_limited(&build_known_tree_1(), 2, 3, true, 2),
+                   Err(ProfileError::DepthLimitExceeded{max_depth: 2}));
+        let deep = (0..1500).fold(Tree::new_str("leaf"), |t, _| Tree::new_str("node").add_node(t));
+        assert!(pqgram_profile_depth_limited(&deep, 2, 3, false, 1000).is_err());
+        assert!(pqgram_profile_depth_limited(&build_known_tree_1(), 0, 3, true, 10).is_err());
+        // A limit beyond what the stack could recurse through still works.
+        let chain = deep_chain(200_000);
+        assert_eq!(pqgram_profile_depth_limited(&chain, 2, 3, false, 1_000_000).unwrap().len(), 600_001);
+        assert_eq!(pqgram_profile_depth_limited(&chain, 2, 3, false, 200_000),
+                   Err(ProfileError::DepthLimitExceeded{max_depth: 200_000}));
+    }
+
+    // A LabelledTree whose links can be rewired after construction, so a cycle can be made.
+    struct LinkedNode {
+        label: String,
+        children: RefCell<Vec<&'static LinkedNode>>,
+    }
+
+    impl LabelledTree<String> for LinkedNode {
+        fn label(&self) -> Node<String> {
+            Node::Label(self.label.clone())
+        }
+        fn children(&self) -> Vec<&Self> {
+            self.children.borrow().clone()
+        }
+    }
+
+    #[test]
+    fn test_pqgram_profile_acyclic() {
+        let leak = |label: &str| -> &'static LinkedNode {
+            Box::leak(Box::new(LinkedNode{label: label.to_string(), children: RefCell::new(vec![])}))
+        };
+        let (a, b, c) = (leak("a"), leak("b"), leak("c"));
+        a.children.borrow_mut().extend(vec![b, c]);
+        b.children.borrow_mut().push(c);
+        // c is shared by a and b but is nobody's ancestor, so this is fine.
+        assert_eq!(pqgram_profile_acyclic(a, 2, 2, true).unwrap().len(), 7);
+        c.children.borrow_mut().push(a);
+        assert_eq!(pqgram_profile_acyclic(a, 2, 2, true), Err(ProfileError::CycleDetected));
+        let selfish = leak("s");
+        selfish.children.borrow_mut().push(selfish);
+        assert_eq!(pqgram_profile_acyclic(selfish, 2, 2, true), Err(ProfileError::CycleDetected));
+        assert_eq!(pqgram_profile_acyclic(&deep_chain(200_000), 2, 3, false).unwrap().len(), 600_001);
+    }
+
+    #[test]
+    fn test_group_by_anchor() {
+        let prof = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let groups = group_by_anchor(&prof);
+        let sizes: Vec<(String, usize)> = groups.iter().map(|(anchor, grams)| match *anchor {
+            Node::Label(ref l) => (l.clone(), grams.len()),
+            Node::Filler => panic!("Filler can't anchor a gram"),
+        }).collect();
+        assert_eq!(sizes, vec![("a".to_string(), 9), ("b".to_string(), 2),
+                               ("c".to_string(), 1), ("e".to_string(), 1)]);
+        assert!(groups.values().flat_map(|g| g.iter()).all(|g| g.ancestors().len() == 2 && g.siblings().len() == 3));
+    }
+
+    #[test]
+    fn test_pqgram_distance_by_anchor() {
+        let prof1 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof3 = pqgram_profile(build_known_tree_2(), 2, 3, true);
+        let inner = |n: &Node<String>| *n == Node::Label("b".to_string()) || *n == Node::Label("e".to_string());
+        let root = |n: &Node<String>| *n == Node::Label("a".to_string());
+        let inner_dist = pqgram_distance_by_anchor(&prof1, &prof3, inner);
+        let root_dist = pqgram_distance_by_anchor(&prof1, &prof3, root);
+        assert_eq!(f64_round_2dp(inner_dist), 0.);
+        assert_eq!(f64_round_2dp(root_dist), 0.33);
+    }
+
+    #[test]
+    fn test_pqgram_distance_ensemble() {
+        let prof1 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof3 = pqgram_profile(build_known_tree_2(), 2, 3, true);
+        let half_credit = |l: &PQGram<String>, r: &PQGram<String>, filler: &String| {
+            let (score, order) = default_gram_edit_distance(l, r, filler);
+            (score / 2., order)
+        };
+        let (combined, scores) = pqgram_distance_ensemble(&prof1, &prof3, None, vec![
+            (0.5, Box::new(default_gram_edit_distance)),
+            (0.5, Box::new(half_credit)),
+        ]);
+        let scores: Vec<f64> = scores.into_iter().map(f64_round_2dp).collect();
+        assert_eq!(scores, vec![0.31, 0.65]);
+        assert_eq!(f64_round_2dp(combined), 0.48);
+    }
+
+    fn repeated(label: &str, times: usize) -> Vec<PQGram<String>> {
+        vec![PQGram::new(vec![Node::Label(label.to_string())], vec![Node::Filler]); times]
+    }
+
+    #[test]
+    fn test_intersection_long_runs() {
+        // 20,000 copies of one leaf gram against as many distinct ones: rescanning the
+        // run at every step made this quadratic.
+        let distinct = (0..20_000).fold(Tree::new_str("ul"), |t, i| t.add_node(Tree::new(format!("a{:06}", i))));
+        let repeated = (0..20_000).fold(Tree::new_str("ul"), |t, _| t.add_node(Tree::new_str("li")));
+        let distinct = pqgram_profile(distinct, 2, 3, true);
+        let repeated = pqgram_profile(repeated, 2, 3, true);
+        let expected = counted_pqgram_distance(&CountedProfile::from_profile(&distinct), &CountedProfile::from_profile(&repeated));
+        assert_eq!(pqgram_distance(&distinct, &repeated), expected);
+        assert_eq!(pqgram_distance(&repeated, &distinct), expected);
+        assert_eq!(pqgram_distance(&repeated, &repeated), 0.);
+    }
+
+    #[test]
+    fn test_intersection_bag_semantics() {
+        let intersection = |left: &[PQGram<String>], right: &[PQGram<String>], f: GramDistanceFn<String>| {
+            super::pqgrams::pqgram_profile_intersection(left, right, None, f)
+        };
+        // Three of a gram against two: the smaller multiplicity counts.
+        let left = [repeated("a", 3), repeated("b", 1)].concat();
+        let right = [repeated("a", 2), repeated("b", 2)].concat();
+        assert_eq!(intersection(&left, &right, Box::new(default_gram_edit_distance)), 3.);
+        // A kernel giving partial credit to unequal grams used to credit the lone "y"
+        // once for every "x" it was compared against, for 1.5.
+        let partial = |l: &PQGram<String>, r: &PQGram<String>, filler: &String| {
+            let (score, order) = default_gram_edit_distance(l, r, filler);
+            (if order == cmp::Ordering::Equal { score } else { 0.5 }, order)
+        };
+        assert_eq!(intersection(&repeated("x", 3), &repeated("y", 1), Box::new(partial)), 0.5);
+        // The known trees' distances are unchanged, duplicate grams and all.
+        let prof1 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof2 = pqgram_profile(build_known_tree_2(), 2, 3, true);
+        assert!(prof1.windows(2).any(|w| w[0] == w[1]));
+        assert_eq!(pqgram_distance(&prof1, &prof1), 0.);
+        assert_eq!(f64_round_2dp(pqgram_distance(&prof1, &prof2)), 0.31);
+    }
+
+    #[test]
+    fn test_weighted_distance_is_compensated() {
+        // 20,000 distinct grams each scoring 0.1; naive summation drifts to 1999.9999999992765.
+        let wide = (1..10_001).fold(Tree::new(0u32), |t, i| t.add_node(Tree::new(i)));
+        let prof = pqgram_profile(wide, 1, 1, true);
+        let tenth_credit = |l: &PQGram<u32>, r: &PQGram<u32>, filler: &u32| {
+            let (score, order) = default_gram_edit_distance(l, r, filler);
+            (score / 10., order)
+        };
+        let distance = pqgram_distance_with_fn(&prof, &prof, Some(u32::MAX), tenth_credit);
+        assert_eq!(distance, 1. - 2. * (2000. / 40000.));
+    }
+
+    #[test]
+    fn test_distance_with_capturing_closure() {
+        let prof1 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof3 = pqgram_profile(build_known_tree_2(), 2, 3, true);
+        let mut matched = 0;
+        let distance = pqgram_distance_with_fn(&prof1, &prof3, None, |l: &PQGram<String>, r: &PQGram<String>, filler: &String| {
+            let (score, order) = default_gram_edit_distance(l, r, filler);
+            if order == cmp::Ordering::Equal { matched += 1 }
+            (score, order)
+        });
+        assert_eq!(distance, pqgram_distance(&prof1, &prof3));
+        assert_eq!(matched, 8);
+    }
+
+    #[test]
+    fn test_default_label_is_not_filler() {
+        // Labels of 0, u32's default, used to be compared as if they were fillers.
+        let lone = pqgram_profile(Tree::new(0u32), 2, 1, true);
+        let pair = pqgram_profile(Tree::new(0u32).add_node(Tree::new(0)), 2, 1, true);
+        let by_label = |l: &PQGram<u32>, r: &PQGram<u32>, filler: &u32| {
+            let (l, r) = (l.concat(*filler), r.concat(*filler));
+            (if l == r { 1. } else { 0. }, l.cmp(&r))
+        };
+        assert_eq!(f64_round_2dp(pqgram_distance_with_fn(&lone, &pair, None, by_label)), 0.33);
+        assert_eq!(pqgram_distance(&lone, &pair), 1.);
+        assert_eq!(pqgram_distance(&pair, &pair), 0.);
+    }
+
+    thread_local!(static CLONES: Cell<usize> = const { Cell::new(0) });
+
+    /// A label that counts how often it is cloned.
+    #[derive(Debug,Default,PartialEq,PartialOrd,Eq,Ord)]
+    struct CloneCounted(u32);
+
+    impl Clone for CloneCounted {
+        fn clone(&self) -> CloneCounted {
+            CLONES.with(|c| c.set(c.get() + 1));
+            CloneCounted(self.0)
+        }
+    }
+
+    impl super::ValidGramElement for CloneCounted {}
+
+    #[test]
+    fn test_distance_does_not_clone_labels() {
+        let wide = (1..1_001).fold(Tree::new(CloneCounted(0)), |t, i| t.add_node(Tree::new(CloneCounted(i))));
+        let prof = pqgram_profile_ref(&wide, 2, 3, true);
+        CLONES.with(|c| c.set(0));
+        assert_eq!(pqgram_distance(&prof, &prof), 0.);
+        assert_eq!(CLONES.with(|c| c.get()), 0);
+    }
+
+    #[test]
+    fn test_concat_profiles() {
+        let head = pqgram_profile(Tree::new_str("head").add_node(Tree::new_str("title")), 2, 3, true);
+        let body_1 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let body_2 = pqgram_profile(build_known_tree_2(), 2, 3, true);
+        let doc_1 = concat_profiles(&[&head, &body_1], true);
+        let doc_2 = concat_profiles(&[&head, &body_2], true);
+        assert_eq!(doc_1.len(), head.len() + body_1.len());
+        let dist = pqgram_distance(&doc_1, &doc_2);
+        assert_eq!(f64_round_2dp(dist), 0.24);
+        // The same tree in different fields shares no grams.
+        let swapped = concat_profiles(&[&body_1, &head], true);
+        assert_eq!(f64_round_2dp(pqgram_distance(&doc_1, &swapped)), 1.);
+    }
+
+    #[test]
+    fn test_degenerate_trees() {
+        let single = pqgram_profile(Tree::new_str("a"), 2, 3, true);
+        assert_eq!(flatten_profile(&single, "*".to_string()), vec![vec!["*", "a", "*", "*", "*"]]);
+        let other = pqgram_profile(Tree::new_str("b"), 2, 3, true);
+        assert_eq!(pqgram_distance(&single, &single), 0.);
+        assert_eq!(pqgram_distance(&single, &other), 1.);
+        // p and q beyond the tree's depth and fan-out only add padding.
+        let deep = pqgram_profile(build_known_tree_1(), 5, 6, true);
+        assert_eq!(deep.len(), pqgram_profile(build_known_tree_1(), 1, 6, true).len());
+        assert!(deep.iter().all(|g| g.ancestors().len() == 5 && g.siblings().len() == 6));
+        assert_eq!(pqgram_distance(&deep, &deep), 0.);
+    }
+
+    #[test]
+    #[should_panic(expected = "p and q must both be at least 1")]
+    fn test_pqgram_profile_zero_q() {
+        pqgram_profile(build_known_tree_1(), 2, 0, true);
+    }
+
+    #[test]
+    fn test_empty_trees() {
+        let missing = pqgram_profile_opt::<String, Tree<String>>(None, 2, 3, true);
+        let present = pqgram_profile_opt(Some(build_known_tree_1()), 2, 3, true);
+        assert!(missing.is_empty());
+        assert_eq!(present, pqgram_profile(build_known_tree_1(), 2, 3, true));
+        assert_eq!(pqgram_distance(&missing, &missing), 0.);
+        assert_eq!(pqgram_distance(&missing, &present), 1.);
+        assert_eq!(pqgram_distance(&present, &missing), 1.);
+    }
+
+    #[test]
+    fn test_forest() {
+        let forest: Forest<String> = vec![build_known_tree_1(), Tree::new_str("d")].into_iter().collect();
+        let prof = forest.profile(2, 3, false);
+        // The trees' own grams, plus one per tree and q - 1 trailing grams under the virtual root.
+        let tree_grams = pqgram_profile(build_known_tree_1(), 2, 3, false).len() + 1;
+        assert_eq!(prof.len(), tree_grams + 2 + 2);
+        assert_eq!(flatten_profile(&prof[..1], "*".to_string()), vec![vec!["*", "*", "*", "*", "a"]]);
+        let other = Forest::new().add_tree(build_known_tree_2()).add_tree(Tree::new_str("d"));
+        assert_eq!(forest.distance(&forest.clone(), 2, 3), 0.);
+        assert!(forest.distance(&other, 2, 3) > 0.);
+        assert!(Forest::<String>::new().profile(2, 3, true).is_empty());
+        assert_eq!(Forest::<String>::new().distance(&forest, 2, 3), 1.);
+    }
+
+    #[test]
+    fn test_pqgram_distance_to_profile() {
+        let stored = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        assert_eq!(pqgram_distance_to_profile(&build_known_tree_1(), &stored), 0.);
+        assert_eq!(f64_round_2dp(pqgram_distance_to_profile(&build_known_tree_2(), &stored)), 0.31);
+        // Matches profiling the tree first, whether or not the stored profile is sorted.
+        let unsorted = pqgram_profile(build_known_tree_1(), 2, 3, false);
+        let tree_2 = pqgram_profile(build_known_tree_2(), 2, 3, true);
+        assert_eq!(pqgram_distance_to_profile(&build_known_tree_2(), &unsorted), pqgram_distance(&tree_2, &stored));
+        assert_eq!(pqgram_distance_to_profile(&Tree::new_str("x"), &Profile::new(vec![], 2, 3)), 1.);
+    }
+
+    /// A single path of n + 1 nodes, labelled n at the root down to 0 at the leaf.
+    pub(crate) fn deep_chain(n: u32) -> Tree<u32> {
+        (0..n).fold(Tree::new(0u32), |t, i| Tree::new(i + 1).add_node(t))
+    }
+
+    #[test]
+    fn test_pqgram_profile_deep_chain() {
+        let chain = deep_chain(100_000);
+        let prof = pqgram_profile_ref(&chain, 2, 3, false);
+        // q grams for each of the 100,000 inner nodes, and one for the leaf.
+        assert_eq!(prof.len(), 100_000 * 3 + 1);
+        assert_eq!(prof.last().unwrap().anchor(), &Node::Label(100_000));
+    }
+
+    // The original recursive profiler, kept as a reference for the iterative one.
+    fn recursive_profile(tree: &Tree<String>, q: usize, ancestors: &mut BDeque<Node<String>>) -> Vec<PQGram<String>> {
+        ancestors.push_back(tree.label());
+        let mut siblings = BDeque::new(q);
+        siblings.fill_with(Node::Filler);
+        let mut grams = Vec::new();
+        if tree.children().is_empty() {
+            grams.push(PQGram::new(ancestors.copy_state(), siblings.copy_state()));
+        } else {
+            for child in tree.children() {
+                siblings.push_back(child.label());
+                grams.push(PQGram::new(ancestors.copy_state(), siblings.copy_state()));
+                grams.extend(recursive_profile(child, q, &mut ancestors.clone()));
+            }
+            for _ in 0..q-1 {
+                siblings.push_back(Node::Filler);
+                grams.push(PQGram::new(ancestors.copy_state(), siblings.copy_state()));
+            }
+        }
+        grams
+    }
+
+    // A small random tree from a linear congruential generator, so runs are repeatable.
+    fn lcg_tree(seed: &mut u64, depth: usize) -> Tree<String> {
+        let mut next = |n: u64| { *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407); (*seed >> 33) % n };
+        let label = ["a", "b", "c", "*"][next(4) as usize];
+        let fan_out = if depth == 0 { 0 } else { next(4) };
+        (0..fan_out).fold(Tree::new_str(label), |t, _| t.add_node(lcg_tree(seed, depth - 1)))
+    }
+
+    #[test]
+    fn test_iterative_profile_matches_recursive() {
+        let mut seed = 42;
+        for _ in 0..200 {
+            let tree = lcg_tree(&mut seed, 4);
+            for &(p, q) in &[(1, 1), (2, 3), (3, 2)] {
+                let mut ancestors = BDeque::new(p);
+                ancestors.fill_with(Node::Filler);
+                assert_eq!(pqgram_profile_ref(&tree, p, q, false), recursive_profile(&tree, q, &mut ancestors));
+            }
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_profile_matches_serial() {
+        let mut seed = 7;
+        for _ in 0..50 {
+            let tree = lcg_tree(&mut seed, 5);
+            for &(p, q) in &[(1, 1), (2, 3), (3, 2)] {
+                assert_eq!(super::par_pqgram_profile(&tree, p, q, false), pqgram_profile_ref(&tree, p, q, false));
+                assert!(super::par_pqgram_profile(&tree, p, q, false).is_preorder());
+            }
+        }
+        let trees: Vec<Tree<String>> = (0..8).map(|_| lcg_tree(&mut seed, 3)).collect();
+        let serial: Vec<_> = trees.iter().map(|t| pqgram_profile_ref(t, 2, 3, true)).collect();
+        assert_eq!(super::pqgram_profiles_par(trees, 2, 3, true), serial);
+        let matrix = super::par_pairwise_distances(&serial);
+        for (i, row) in matrix.iter().enumerate() {
+            for (j, &distance) in row.iter().enumerate() {
+                assert_eq!(distance, pqgram_distance(&serial[i], &serial[j]));
+                assert_eq!(distance, matrix[j][i]);
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let prof = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let json = ::serde_json::to_string(&prof).unwrap();
+        assert!(json.contains("\"Filler\""));
+        assert_eq!(::serde_json::from_str::<Profile<String>>(&json).unwrap(), prof);
+        let bytes = ::bincode::serialize(&prof).unwrap();
+        assert_eq!(::bincode::deserialize::<Profile<String>>(&bytes).unwrap(), prof);
+        // A label equal to the default must not come back as a filler, or vice versa.
+        let numeric = Tree::new(0u32).add_node(Tree::new(1)).add_node(Tree::new(0));
+        let prof = pqgram_profile_ref(&numeric, 2, 3, false);
+        let json = ::serde_json::to_string(&numeric).unwrap();
+        assert_eq!(pqgram_profile(::serde_json::from_str::<Tree<u32>>(&json).unwrap(), 2, 3, false), prof);
+        let bytes = ::bincode::serialize(&prof).unwrap();
+        assert_eq!(::bincode::deserialize::<Profile<u32>>(&bytes).unwrap(), prof);
+        let bytes = ::bincode::serialize(&build_known_tree_1()).unwrap();
+        let tree: Tree<String> = ::bincode::deserialize(&bytes).unwrap();
+        assert_eq!(pqgram_profile(tree, 2, 3, true), pqgram_profile(build_known_tree_1(), 2, 3, true));
+    }
+
+    #[test]
+    fn test_profile_equality_ignores_sorted_flag() {
+        let prof = pqgram_profile(Tree::new_str("a"), 2, 3, false);
+        assert_eq!(prof.len(), 1);
+        assert!(!prof.is_sorted());
+        let rebuilt = Profile::new(prof.clone().into_grams(), 2, 3);
+        assert!(rebuilt.is_sorted());
+        assert_eq!(rebuilt, prof);
+        assert!(Profile::new(prof.clone().into_grams(), 2, 3) != Profile::from_parts(vec![], 2, 3, true));
+    }
+
+    #[test]
+    #[should_panic(expected = "p and q must both be at least 1")]
+    fn test_profile_new_rejects_zero_parameters() {
+        Profile::<String>::new(vec![], 0, 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip_unsorted_profile() {
+        let prof = pqgram_profile(Tree::new_str("a"), 2, 3, false);
+        let json = ::serde_json::to_string(&prof).unwrap();
+        assert_eq!(::serde_json::from_str::<Profile<String>>(&json).unwrap(), prof);
+        assert!(!::serde_json::from_str::<Profile<String>>(&json).unwrap().is_preorder());
+        assert_eq!(::serde_json::from_str::<Profile<String>>(&json).unwrap().node_count(), Some(1));
+        let without_count = json.replace(",\"node_count\":1", "");
+        assert!(without_count != json);
+        assert_eq!(::serde_json::from_str::<Profile<String>>(&without_count).unwrap().node_count(), None);
+        let bytes = ::bincode::serialize(&prof).unwrap();
+        assert_eq!(::bincode::deserialize::<Profile<String>>(&bytes).unwrap(), prof);
+        let json = ::serde_json::to_string(&Profile::<String>::from_parts(vec![], 0, 3, true)).unwrap();
+        assert!(::serde_json::from_str::<Profile<String>>(&json).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_checks_profiles() {
+        let prof = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        // A stored sorted flag is recomputed rather than trusted.
+        let mut grams = prof.clone().into_grams();
+        grams.reverse();
+        let json = ::serde_json::to_string(&Profile::new(grams, 2, 3)).unwrap().replace("\"sorted\":false", "\"sorted\":true");
+        assert!(!::serde_json::from_str::<Profile<String>>(&json).unwrap().is_sorted());
+        // Grams of the wrong width are an error, not a panic later on.
+        let json = ::serde_json::to_string(&prof).unwrap().replace("\"q\":3", "\"q\":4");
+        let err = ::serde_json::from_str::<Profile<String>>(&json).unwrap_err();
+        assert!(err.to_string().contains("was not built with p = 2, q = 4"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip_results() {
+        let prof1 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof2 = pqgram_profile(build_known_tree_2(), 2, 3, true);
+        let explanation = super::explain(&prof1, &prof2);
+        let json = ::serde_json::to_string(&explanation).unwrap();
+        assert_eq!(::serde_json::from_str::<super::Explanation<String>>(&json).unwrap(), explanation);
+        let counted = CountedProfile::from_profile(&prof1);
+        let json = ::serde_json::to_string(&counted).unwrap();
+        assert_eq!(::serde_json::from_str::<CountedProfile<String>>(&json).unwrap(), counted);
+        // The stored length is recomputed, and counts are checked against p and q.
+        let tampered = json.replace(&format!("\"len\":{}", counted.len()), "\"len\":1");
+        assert!(tampered != json);
+        let reread = ::serde_json::from_str::<CountedProfile<String>>(&tampered).unwrap();
+        assert_eq!(reread.len(), counted.len());
+        assert_eq!(counted_pqgram_distance(&reread, &reread), 0.);
+        assert!(::serde_json::from_str::<CountedProfile<String>>(&json.replace("\"q\":3", "\"q\":2")).is_err());
+        assert!(::serde_json::from_str::<CountedProfile<String>>(&json.replace("\"q\":3", "\"q\":0")).is_err());
+        let exact = pqgram_distance_exact(&prof1, &prof2);
+        let json = ::serde_json::to_string(&exact).unwrap();
+        assert_eq!(::serde_json::from_str::<ExactDistance>(&json).unwrap(), exact);
+        assert!(::serde_json::from_str::<ExactDistance>(r#"{"shared":2,"total":3}"#).is_err());
+        let mut versions = super::VersionedTree::new(2, 3);
+        versions.push(build_known_tree_1());
+        let drift = versions.push(build_known_tree_2());
+        let json = ::serde_json::to_string(&drift).unwrap();
+        assert_eq!(::serde_json::from_str::<super::VersionDrift>(&json).unwrap(), drift);
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_random_tree() {
+        use rand::{Rng, SeedableRng};
+        use rand::rngs::StdRng;
+        fn depth(tree: &Tree<u32>) -> usize {
+            tree.children.iter().map(|c| 1 + depth(c)).max().unwrap_or(0)
+        }
+        fn size(tree: &Tree<u32>) -> usize {
+            1 + tree.children.iter().map(size).sum::<usize>()
+        }
+        let params = super::RandomTreeParams{max_depth: 4, max_children: 3, max_nodes: 30, label: |rng: &mut StdRng| rng.gen_range(0..5)};
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let tree = Tree::random(&mut rng, &params);
+            assert!(depth(&tree) <= 4 && size(&tree) <= 30);
+            assert!(tree.children.len() <= 3);
+            let prof = pqgram_profile_ref(&tree, 2, 3, true);
+            assert_eq!(pqgram_distance(&prof, &prof), 0.);
+        }
+        let again = |seed| Tree::random(&mut StdRng::seed_from_u64(seed), &params);
+        assert_eq!(again(9), again(9));
+        let lone = super::RandomTreeParams{max_nodes: 0, ..params};
+        assert_eq!(size(&Tree::random(&mut rng, &lone)), 1);
+    }
+
+    #[test]
+    fn test_bracket_notation() {
+        let tree = Tree::from_bracket("{a{a{e}{b}}{b}{c}}").unwrap();
+        assert_eq!(tree, build_known_tree_1());
+        assert_eq!(flatten_profile(&pqgram_profile(tree, 2, 3, true), "*".to_string()), known_profile_1());
+        assert_eq!(build_known_tree_1().to_bracket(), "{a{a{e}{b}}{b}{c}}");
+        let awkward = Tree::new_str("a{b}").add_node(Tree::new_str("")).add_node(Tree::new_str("c\\").add_node(Tree::new_str("d e")));
+        assert_eq!(awkward.to_bracket(), "{a\\{b\\}{}{c\\\\{d e}}}");
+        assert_eq!(Tree::from_bracket(&awkward.to_bracket()).unwrap(), awkward);
+        let mut seed = 3;
+        for _ in 0..50 {
+            let tree = lcg_tree(&mut seed, 4);
+            assert_eq!(Tree::from_bracket(&tree.to_bracket()).unwrap(), tree);
+        }
+        assert_eq!(Tree::from_bracket(" {a}\n").unwrap(), Tree::new_str("a"));
+        // Whitespace between nodes is layout; within a label it is content.
+        let spaced = Tree::new_str("a").add_node(Tree::new_str("b")).add_node(Tree::new_str("c"));
+        assert_eq!(Tree::from_bracket("{a{b} {c}}").unwrap(), spaced);
+        assert_eq!(Tree::from_bracket("{a{b}\n  {c}\n}").unwrap(), spaced);
+        assert_eq!(Tree::from_bracket("{a b{c d}}").unwrap(), Tree::new_str("a b").add_node(Tree::new_str("c d")));
+    }
+
+    #[test]
+    fn test_bracket_notation_errors() {
+        let position = |s: &str| Tree::from_bracket(s).unwrap_err().position;
+        assert_eq!(position(""), 0);
+        assert_eq!(position("a"), 0);
+        assert_eq!(position("}"), 0);
+        assert_eq!(position("{a{b}"), 5);
+        assert_eq!(position("{a}}"), 3);
+        assert_eq!(position("{a}{b}"), 3);
+        assert_eq!(position("{a{b}c}"), 5);
+        assert_eq!(position("{a{b} c}"), 6);
+        assert_eq!(position("{a\\"), 2);
+    }
+
+    #[test]
+    fn test_pqgram_profile_order() {
+        let expected: Vec<Vec<String>> = vec![
+            vec!["*", "a", "*", "*", "a"],
+            vec!["a", "a", "*", "*", "e"],
+            vec!["a", "e", "*", "*", "*"],
+            vec!["a", "a", "*", "e", "b"],
+            vec!["a", "b", "*", "*", "*"],
+            vec!["a", "a", "e", "b", "*"],
+            vec!["a", "a", "b", "*", "*"],
+            vec!["*", "a", "*", "a", "b"],
+            vec!["a", "b", "*", "*", "*"],
+            vec!["*", "a", "a", "b", "c"],
+            vec!["a", "c", "*", "*", "*"],
+            vec!["*", "a", "b", "c", "*"],
+            vec!["*", "a", "c", "*", "*"],
+            ].iter().map(|v| v.iter().map(|s| s.to_string()).collect()).collect();
+        assert_eq!(expected, flatten_profile(&pqgram_profile(build_known_tree_1(), 2, 3, false), "*".to_string()));
+    }
+
+    #[test]
+    fn test_profile_node_count() {
+        assert_eq!(pqgram_profile(build_known_tree_1(), 2, 3, true).node_count(), Some(6));
+        assert_eq!(pqgram_profile_distinct(&build_known_tree_1(), 2, 3).node_count(), Some(6));
+        assert_eq!(Forest(vec![build_known_tree_1(), Tree::new_str("d")]).profile(2, 3, false).node_count(), Some(7));
+        assert_eq!(pqgram_profile_opt::<String, Tree<String>>(None, 2, 3, true).node_count(), Some(0));
+        assert_eq!(pqgram_profile_ref(&deep_chain(1000), 2, 3, false).node_count(), Some(1001));
+        let prof = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        assert_eq!(Profile::new(prof.clone().into_grams(), 2, 3).node_count(), None);
+        assert_eq!(Profile::new(prof.into_grams(), 2, 3).with_node_count(6).node_count(), Some(6));
+    }
+
+    #[test]
+    fn test_pqgram_distance_size_aware() {
+        let form = || Tree::new_str("form").add_node(Tree::new_str("input")).add_node(Tree::new_str("button"));
+        let page = (0..20).fold(Tree::new_str("body"), |t, _| t.add_node(Tree::new_str("p"))).add_node(form());
+        let (small, large) = (pqgram_profile(form(), 2, 3, true), pqgram_profile(page, 2, 3, true));
+        let plain = pqgram_distance(&small, &large);
+        let sized = pqgram_distance_size_aware(&small, &large);
+        assert!(sized > plain && sized < 1.);
+        assert_eq!(f64_round_2dp(sized), f64_round_2dp(1. - (1. - plain) * 3. / 24.));
+        assert_eq!(pqgram_distance_size_aware(&small, &small), 0.);
+        let empty = pqgram_profile_opt::<String, Tree<String>>(None, 2, 3, true);
+        assert_eq!(pqgram_distance_size_aware(&empty, &empty), 0.);
+        assert_eq!(pqgram_distance_size_aware(&empty, &small), 1.);
+    }
+
+    #[test]
+    #[should_panic(expected = "node counts")]
+    fn test_pqgram_distance_size_aware_needs_node_counts() {
+        let prof = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        pqgram_distance_size_aware(&prof, &Profile::new(prof.clone().into_grams(), 2, 3));
+    }
+
+    #[test]
+    fn test_profile_is_preorder() {
+        let mut prof = pqgram_profile(build_known_tree_1(), 2, 3, false);
+        assert!(prof.is_preorder());
+        assert!(!pqgram_profile(build_known_tree_1(), 2, 3, true).is_preorder());
+        assert!(!Profile::new(prof.clone().into_grams(), 2, 3).is_preorder());
+        assert!(Forest(vec![build_known_tree_1()]).profile(2, 3, false).is_preorder());
+        prof.sort_in_place();
+        assert!(!prof.is_preorder());
+    }
+
+    #[test]
+    fn test_sort_profile() {
+        let mut prof = pqgram_profile(build_known_tree_1(), 2, 3, false).into_grams();
+        sort_profile(&mut prof);
+        assert_eq!(prof, pqgram_profile(build_known_tree_1(), 2, 3, true));
+    }
+
+    #[test]
+    fn test_pqgram_profile_each() {
+        let mut streamed = Vec::new();
+        pqgram_profile_each(&build_known_tree_1(), 2, 3, |g| streamed.push(g));
+        assert_eq!(streamed, pqgram_profile(build_known_tree_1(), 2, 3, false));
+    }
+
+    #[test]
+    fn test_pqgram_profile_distinct() {
+        let mut expected = pqgram_profile(build_known_tree_1(), 2, 3, true).into_grams();
+        expected.dedup();
+        let distinct = pqgram_profile_distinct(&build_known_tree_1(), 2, 3);
+        assert_eq!(distinct, expected);
+        assert_eq!(distinct.len(), 12);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_sort_profile() {
+        let mut prof = pqgram_profile(build_known_tree_1(), 2, 3, false).into_grams();
+        super::par_sort_profile(&mut prof);
+        assert_eq!(prof, pqgram_profile(build_known_tree_1(), 2, 3, true));
+    }
+
+    #[test]
+    fn test_pqgram_distance_with_budget() {
+        let prof1 = pqgram_profile(build_known_tree_1(), 2, 3, true);
+        let prof3 = pqgram_profile(build_known_tree_2(), 2, 3, true);
+        let unlimited = pqgram_distance_with_budget(&prof1, &prof3, None, default_gram_edit_distance, Budget::default());
+        assert_eq!(unlimited, BudgetedDistance::Exact(pqgram_distance(&prof1, &prof3)));
+        // The first two grams of both profiles match, the third does not.
+        let budget = Budget{max_comparisons: Some(2), ..Budget::default()};
+        let partial = pqgram_distance_with_budget(&prof1, &prof3, None, Box::new(default_gram_edit_distance), budget);
+        assert_eq!(partial, BudgetedDistance::Partial(0.));
+        let budget = Budget{max_comparisons: Some(4), ..Budget::default()};
+        let partial = pqgram_distance_with_budget(&prof1, &prof3, None, Box::new(default_gram_edit_distance), budget);
+        assert_eq!(f64_round_2dp(match partial { BudgetedDistance::Partial(d) => d, _ => panic!("Budget not applied") }), 0.33);
+        let expired = Budget{deadline: Some(Instant::now()), ..Budget::default()};
+        let none = pqgram_distance_with_budget(&prof1, &prof3, None, Box::new(default_gram_edit_distance), expired);
+        assert_eq!(none, BudgetedDistance::Partial(1.));
+    }
+
+    #[test]
+    fn test_canonical_bytes() {
+        let tree_1 = build_known_tree_1();
+        assert_eq!(tree_1.canonical_bytes(), tree_1.clone().canonical_bytes());
+        assert!(tree_1.canonical_bytes() != build_known_tree_2().canonical_bytes());
+        // Same labels in preorder, different shape.
+        let flat = Tree::new_str("a").add_node(Tree::new_str("b")).add_node(Tree::new_str("c"));
+        let nested = Tree::new_str("a").add_node(Tree::new_str("b").add_node(Tree::new_str("c")));
+        assert!(flat.canonical_bytes() != nested.canonical_bytes());
+        assert_eq!(Tree::new(7u32).canonical_bytes(), vec![7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(tree_1.profile_key(2, 3, None) != tree_1.profile_key(2, 4, None));
+        assert!(tree_1.profile_key(2, 3, None) != tree_1.profile_key(2, 3, Some(&"*".to_string())));
+        assert!(tree_1.profile_key(2, 3, None).ends_with(&tree_1.canonical_bytes()));
+        // Label then child count for each node in preorder, however deep.
+        let bytes = deep_chain(100_000).canonical_bytes();
+        assert_eq!(bytes.len(), 100_001 * 12);
+        assert_eq!(&bytes[..12], &[0xa0, 0x86, 0x01, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&bytes[bytes.len() - 12..], &[0; 12]);
+    }
+
+    #[test]
+    fn test_pqgram_distance_normalized() {
+        let upper = Tree::new_str("A").add_node(Tree::new_str("B")).add_node(Tree::new_str("C"));
+        let lower = Tree::new_str("a").add_node(Tree::new_str("b")).add_node(Tree::new_str("c"));
+        let upper = pqgram_profile(upper, 2, 3, true);
+        let lower = pqgram_profile(lower, 2, 3, true);
+        assert_eq!(pqgram_distance(&upper, &lower), 1.);
+        let fold = |l: &String| l.to_lowercase();
+        assert_eq!(pqgram_distance_normalized(&upper, &lower, fold), 0.);
+        assert_eq!(map_profile_labels(&upper, fold, true), lower);
+        let lengths = map_profile_labels(&upper, |l: &String| l.len() as u32, true);
+        assert!(lengths.iter().all(|g| g.anchor() == &Node::Label(1)));
+    }
 }
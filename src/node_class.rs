@@ -0,0 +1,90 @@
+use default_tree::CanonicalLabel;
+use pqgrams::{Node, PQGram, ValidGramElement, GramDistanceFn, default_gram_edit_distance};
+
+/// A common label space for markup trees, so that adapters for HTML, XML and the
+/// like produce comparable profiles. Text and attribute nodes are kept apart from
+/// elements, so a text node reading "div" never matches a `div` element.
+#[derive(Clone,Debug,PartialEq,PartialOrd,Eq,Ord,Hash)]
+pub enum NodeClass {
+    Element(String),
+    Text(String),
+    Attr(String, String),
+}
+
+impl Default for NodeClass {
+    fn default() -> NodeClass {
+        NodeClass::Element(String::new())
+    }
+}
+
+impl ValidGramElement for NodeClass {}
+
+impl CanonicalLabel for NodeClass {
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        match *self {
+            NodeClass::Element(ref name) => { out.push(0); name.write_canonical(out) },
+            NodeClass::Text(ref text) => { out.push(1); text.write_canonical(out) },
+            NodeClass::Attr(ref key, ref value) => {
+                out.push(2);
+                key.write_canonical(out);
+                value.write_canonical(out)
+            },
+        }
+    }
+}
+
+/// Weights given to matching grams by the class of their anchor node.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct ClassWeights {
+    pub element: f64,
+    pub text: f64,
+    pub attr: f64,
+}
+
+impl Default for ClassWeights {
+    fn default() -> ClassWeights {
+        ClassWeights{element: 1., text: 1., attr: 1.}
+    }
+}
+
+/// Build a gram distance function for pqgram_distance_with_fn that credits a match
+/// by the weight of its anchor's class instead of a flat 1, e.g. to let structure
+/// count for more than text content. Weights should lie between 0 and 1.
+pub fn class_weighted_gram_distance(weights: ClassWeights) -> GramDistanceFn<NodeClass> {
+    Box::new(move |left: &PQGram<NodeClass>, right: &PQGram<NodeClass>, filler: &NodeClass| {
+        let (score, order) = default_gram_edit_distance(left, right, filler);
+        let weight = match *left.anchor() {
+            Node::Label(NodeClass::Element(_)) | Node::Filler => weights.element,
+            Node::Label(NodeClass::Text(_)) => weights.text,
+            Node::Label(NodeClass::Attr(_, _)) => weights.attr,
+        };
+        (score * weight, order)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use default_tree::Tree;
+    use pqgrams::{pqgram_profile, pqgram_distance, pqgram_distance_with_fn};
+    use super::{NodeClass, ClassWeights, class_weighted_gram_distance};
+
+    fn page(text: &str) -> Tree<NodeClass> {
+        Tree::new(NodeClass::Element("p".to_string()))
+            .add_node(Tree::new(NodeClass::Attr("class".to_string(), "intro".to_string())))
+            .add_node(Tree::new(NodeClass::Text(text.to_string())))
+    }
+
+    #[test]
+    fn test_class_weighted_distance() {
+        let a = pqgram_profile(page("hello"), 2, 2, true);
+        let b = pqgram_profile(page("goodbye"), 2, 2, true);
+        let filler = Some(NodeClass::Element("*".to_string()));
+        let plain = pqgram_distance(&a, &b);
+        let same = pqgram_distance_with_fn(&a, &a, filler.clone(), class_weighted_gram_distance(ClassWeights::default()));
+        let no_attrs = ClassWeights{attr: 0., ..ClassWeights::default()};
+        let weighted = pqgram_distance_with_fn(&a, &b, filler, class_weighted_gram_distance(no_attrs));
+        assert_eq!(same, 0.);
+        assert!(weighted > plain);
+        assert!(NodeClass::Text("div".to_string()) != NodeClass::Element("div".to_string()));
+    }
+}
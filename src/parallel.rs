@@ -0,0 +1,75 @@
+use rayon::prelude::*;
+use bdeque::BDeque;
+use pqgrams::{Node, PQGram, Profile, LabelledTree, ValidGramElement, _profile_subtree, sort_profile, pqgram_profile, pqgram_distance};
+
+/// As pqgram_profile_ref, but each of the root's child subtrees is profiled on the
+/// rayon thread pool. The root's own sibling windows are built first, so unsorted
+/// output is gram for gram the same as pqgram_profile_ref's. Only the root's
+/// children are split, so this pays off for wide trees of large subtrees, such as a
+/// document whose root holds its sections. Panics if p or q is zero.
+pub fn par_pqgram_profile<L, T>(tree: &T, p: usize, q: usize, sort: bool) -> Profile<L>
+    where L: ValidGramElement + Send + Sync, T: LabelledTree<L> + Sync
+{
+    assert!(p > 0 && q > 0, "p and q must both be at least 1, got p = {}, q = {}", p, q);
+    let mut ancestors = BDeque::<Node<L>>::new(p);
+    ancestors.fill_with(Node::Filler);
+    ancestors.push_back(tree.label());
+    let children = tree.children();
+    let mut siblings = BDeque::<Node<L>>::new(q);
+    siblings.fill_with(Node::Filler);
+    let mut grams = Vec::new();
+    let mut nodes = 1;
+    if children.is_empty() {
+        grams.push(PQGram::new(ancestors.copy_state(), siblings.copy_state()));
+    } else {
+        // The window ending at each child spans its earlier siblings, so these are
+        // made in order up front and each child's chunk starts with its own.
+        let windows: Vec<Vec<Node<L>>> = children.iter().map(|child| {
+            siblings.push_back(child.label());
+            siblings.copy_state()
+        }).collect();
+        let chunks: Vec<(Vec<PQGram<L>>, usize)> = children.par_iter().zip(windows).map(|(child, window)| {
+            let mut chunk = vec![PQGram::new(ancestors.copy_state(), window)];
+            let child_nodes = _profile_subtree(*child, q, &mut ancestors.clone(), &mut |g| chunk.push(g));
+            (chunk, child_nodes)
+        }).collect();
+        for (chunk, child_nodes) in chunks {
+            grams.extend(chunk);
+            nodes += child_nodes;
+        }
+        for _ in 0..q-1 {
+            siblings.push_back(Node::Filler);
+            grams.push(PQGram::new(ancestors.copy_state(), siblings.copy_state()));
+        }
+    }
+    if sort { sort_profile(&mut grams) }
+    Profile::from_profiler(grams, p, q, sort, nodes)
+}
+
+/// Profile many trees at once, one tree per rayon task. Profiles are returned in the
+/// order the trees were given.
+pub fn pqgram_profiles_par<L, T>(trees: Vec<T>, p: usize, q: usize, sort: bool) -> Vec<Profile<L>>
+    where L: ValidGramElement + Send, T: LabelledTree<L> + Send
+{
+    trees.into_par_iter().map(|tree| pqgram_profile(tree, p, q, sort)).collect()
+}
+
+/// The distance between every pair of profiles, as a symmetric matrix with zeros on
+/// the diagonal, computed on the rayon thread pool. Each pair is compared once.
+/// Panics if the profiles were built with different p or q.
+pub fn par_pairwise_distances<L>(profiles: &[Profile<L>]) -> Vec<Vec<f64>>
+    where L: ValidGramElement + Send + Sync
+{
+    let n = profiles.len();
+    let upper: Vec<Vec<f64>> = (0..n).into_par_iter().map(|i| {
+        (i+1..n).map(|j| pqgram_distance(&profiles[i], &profiles[j])).collect()
+    }).collect();
+    let mut matrix = vec![vec![0.; n]; n];
+    for (i, row) in upper.into_iter().enumerate() {
+        for (offset, distance) in row.into_iter().enumerate() {
+            matrix[i][i + 1 + offset] = distance;
+            matrix[i + 1 + offset][i] = distance;
+        }
+    }
+    matrix
+}
@@ -1,6 +1,14 @@
 use std::fmt;
 use std::cmp;
 use std::default;
+use std::error;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::borrow::Cow;
+use std::mem;
+use std::ops;
+use std::slice;
+use std::vec;
+use std::time::Instant;
 use bdeque::BDeque;
 
 
@@ -13,8 +21,10 @@ pub trait ValidGramElement: fmt::Debug + cmp::Ord + Clone + default::Default {}
 /// A single tree node that may form part of a 'gram. PQGrams include
 /// filler labels for absent nodes in either dimension (usual notation is '*')
 /// so this enum lets the PQgram profile contain either while allowing literal
-/// '*' as a label.
+/// '*' as a label. With the serde feature, Filler serializes as a variant of its
+/// own, so it never collides with the label type's default.
 #[derive(Copy,Clone,Debug,PartialEq,PartialOrd,Eq,Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
 pub enum Node<L: ValidGramElement> {
     Filler,
     Label(L),
@@ -22,6 +32,7 @@ pub enum Node<L: ValidGramElement> {
 
 /// A single 'gram in a profile.
 #[derive(Clone,Debug,PartialEq,PartialOrd,Eq,Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
 pub struct PQGram<L: ValidGramElement> {
     ancestors: Vec<Node<L>>,
     siblings: Vec<Node<L>>,
@@ -33,139 +44,985 @@ impl<L: ValidGramElement> PQGram<L> {
         PQGram{ancestors: ps, siblings: qs}
     }
 
+    /// The ancestor nodes, root-most first. The last of these is the anchor.
+    pub fn ancestors(&self) -> &[Node<L>] {
+        &self.ancestors
+    }
+
+    /// The sibling window below the anchor.
+    pub fn siblings(&self) -> &[Node<L>] {
+        &self.siblings
+    }
+
+    /// The node this gram was built around: the last of its ancestors.
+    /// Panics for grams built with p = 0, which have no ancestors.
+    pub fn anchor(&self) -> &Node<L> {
+        self.ancestors.last().expect("PQGram has no ancestors, so no anchor")
+    }
+
     /// Concatenate ancestor and sibling nodes, replacing "Filler" nodes
     /// with the clones of filler_as. By convention string filler nodes
     /// might be represented "*" (as in the paper).
     pub fn concat(&self, filler_as: L) -> Vec<L> {
         let mut bits: Vec<L> = Vec::new();  // TODO; sized
         for a in self.ancestors.iter().chain(self.siblings.iter()) {
-            bits.push(match a {
-                &Node::Label(ref v) => v.clone(),
-                &Node::Filler => filler_as.clone(),
+            bits.push(match *a {
+                Node::Label(ref v) => v.clone(),
+                Node::Filler => filler_as.clone(),
             })
         };
         bits
     }
 }
 
+/// A pq-gram profile: a tree's grams along with the p and q they were built with and
+/// whether they are sorted, so that profiles built differently are never compared
+/// by mistake. Derefs to a slice of its grams, so it can be iterated or passed on
+/// wherever a `&[PQGram<L>]` is expected. Profiles are equal when their grams, p
+/// and q are; whether they are known to be sorted or in preorder is not compared.
+#[derive(Clone,Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ProfileData<L>"))]
+pub struct Profile<L: ValidGramElement> {
+    grams: Vec<PQGram<L>>,
+    p: usize,
+    q: usize,
+    sorted: bool,
+    // Only the profiler can vouch for its own order, so this is not stored.
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
+    preorder: bool,
+    node_count: Option<usize>,
+}
+
+impl<L: ValidGramElement> PartialEq for Profile<L> {
+    fn eq(&self, other: &Profile<L>) -> bool {
+        self.p == other.p && self.q == other.q && self.grams == other.grams
+    }
+}
+
+impl<L: ValidGramElement> Eq for Profile<L> {}
+
+/// Profile as it is deserialized, before its grams are checked against p and q. The
+/// stored sorted flag is read but not trusted; it is worked out again from the grams.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct ProfileData<L: ValidGramElement> {
+    grams: Vec<PQGram<L>>,
+    p: usize,
+    q: usize,
+    #[allow(dead_code)]
+    sorted: bool,
+    #[serde(default)]
+    node_count: Option<usize>,
+}
+
+#[cfg(feature = "serde")]
+impl<L: ValidGramElement> ::std::convert::TryFrom<ProfileData<L>> for Profile<L> {
+    type Error = String;
+
+    fn try_from(data: ProfileData<L>) -> Result<Profile<L>, String> {
+        Ok(Profile::_checked(data.grams, data.p, data.q)?.with_node_count_opt(data.node_count))
+    }
+}
+
+impl<L: ValidGramElement> Profile<L> {
+    /// Wrap grams built with the given p and q, e.g. ones read back from storage.
+    /// Whether they are sorted is worked out here. Panics if p or q is zero, or if a
+    /// gram has the wrong number of ancestors or siblings for p and q.
+    pub fn new(grams: Vec<PQGram<L>>, p: usize, q: usize) -> Profile<L> {
+        Profile::_checked(grams, p, q).unwrap_or_else(|message| panic!("{}", message))
+    }
+
+    fn _checked(grams: Vec<PQGram<L>>, p: usize, q: usize) -> Result<Profile<L>, String> {
+        if p == 0 || q == 0 {
+            return Err(format!("p and q must both be at least 1, got p = {}, q = {}", p, q))
+        }
+        if let Some(gram) = grams.iter().find(|g| g.ancestors.len() != p || g.siblings.len() != q) {
+            return Err(format!("gram {:?} was not built with p = {}, q = {}", gram, p, q))
+        }
+        let sorted = grams.windows(2).all(|w| w[0] <= w[1]);
+        Ok(Profile{grams, p, q, sorted, preorder: false, node_count: None})
+    }
+
+    pub(crate) fn from_parts(grams: Vec<PQGram<L>>, p: usize, q: usize, sorted: bool) -> Profile<L> {
+        Profile{grams, p, q, sorted, preorder: false, node_count: None}
+    }
+
+    /// Grams straight from the profiler, which are in preorder unless it sorted them,
+    /// along with the number of nodes profiled.
+    pub(crate) fn from_profiler(grams: Vec<PQGram<L>>, p: usize, q: usize, sorted: bool, node_count: usize) -> Profile<L> {
+        Profile{grams, p, q, sorted, preorder: !sorted, node_count: Some(node_count)}
+    }
+
+    /// Builder-pattern helper recording how many nodes the profiled tree had, e.g. for
+    /// a profile read back from storage without one.
+    pub fn with_node_count(self, node_count: usize) -> Profile<L> {
+        self.with_node_count_opt(Some(node_count))
+    }
+
+    pub(crate) fn with_node_count_opt(mut self, node_count: Option<usize>) -> Profile<L> {
+        self.node_count = node_count;
+        self
+    }
+
+    pub fn p(&self) -> usize {
+        self.p
+    }
+
+    pub fn q(&self) -> usize {
+        self.q
+    }
+
+    pub fn is_sorted(&self) -> bool {
+        self.sorted
+    }
+
+    /// Whether the grams are in the order the profiler emits them when not sorting,
+    /// as documented on pqgram_profile. Only profiles fresh from an unsorted
+    /// pqgram_profile, par_pqgram_profile or Forest::profile are known to be; a
+    /// profile from Profile::new or deserialization, or one that has been sorted,
+    /// is not.
+    pub fn is_preorder(&self) -> bool {
+        self.preorder
+    }
+
+    /// The number of nodes in the profiled tree, if known. The profilers record it;
+    /// Profile::new does not.
+    pub fn node_count(&self) -> Option<usize> {
+        self.node_count
+    }
+
+    pub fn as_slice(&self) -> &[PQGram<L>] {
+        &self.grams
+    }
+
+    pub fn into_grams(self) -> Vec<PQGram<L>> {
+        self.grams
+    }
+
+    /// Sort the grams unless they already are; see sort_profile.
+    pub fn sort_in_place(&mut self) {
+        if !self.sorted {
+            sort_profile(&mut self.grams);
+            self.sorted = true;
+            self.preorder = false;
+        }
+    }
+
+    /// As group_by_anchor.
+    pub fn group_by_anchor(&self) -> BTreeMap<Node<L>, Vec<&PQGram<L>>> {
+        group_by_anchor(&self.grams)
+    }
+
+    /// The grams in PQGram order, borrowed if they already are sorted.
+    fn sorted_grams(&self) -> Cow<'_, [PQGram<L>]> {
+        if self.sorted {
+            Cow::Borrowed(&self.grams)
+        } else {
+            let mut grams = self.grams.clone();
+            sort_profile(&mut grams);
+            Cow::Owned(grams)
+        }
+    }
+}
+
+impl<L: ValidGramElement> ops::Deref for Profile<L> {
+    type Target = [PQGram<L>];
+
+    fn deref(&self) -> &[PQGram<L>] {
+        &self.grams
+    }
+}
+
+/// Profiles equal a plain list of grams when their grams are equal.
+impl<L: ValidGramElement> PartialEq<Vec<PQGram<L>>> for Profile<L> {
+    fn eq(&self, other: &Vec<PQGram<L>>) -> bool {
+        self.grams == *other
+    }
+}
+
+impl<L: ValidGramElement> PartialEq<Profile<L>> for Vec<PQGram<L>> {
+    fn eq(&self, other: &Profile<L>) -> bool {
+        *self == other.grams
+    }
+}
+
+impl<'a, L: ValidGramElement> IntoIterator for &'a Profile<L> {
+    type Item = &'a PQGram<L>;
+    type IntoIter = slice::Iter<'a, PQGram<L>>;
+
+    fn into_iter(self) -> slice::Iter<'a, PQGram<L>> {
+        self.grams.iter()
+    }
+}
+
+/// Errors returned by the checked profiling entry points.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum ProfileError {
+    /// The profile would hold more grams than the caller allowed.
+    TooManyGrams{ grams: usize, max_grams: usize },
+    /// Sizing the profile overflowed usize arithmetic.
+    SizeOverflow,
+    /// p or q was zero.
+    InvalidParameters{ p: usize, q: usize },
+    /// The tree is nested deeper than the caller allowed.
+    DepthLimitExceeded{ max_depth: usize },
+    /// A node is among its own descendants, so the tree is not a tree.
+    CycleDetected,
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProfileError::TooManyGrams{grams, max_grams} =>
+                write!(f, "profile would contain {} grams, more than the maximum of {}", grams, max_grams),
+            ProfileError::SizeOverflow => write!(f, "profile size overflows usize"),
+            ProfileError::InvalidParameters{p, q} =>
+                write!(f, "p and q must both be at least 1, got p = {}, q = {}", p, q),
+            ProfileError::DepthLimitExceeded{max_depth} =>
+                write!(f, "tree is nested more than {} levels deep", max_depth),
+            ProfileError::CycleDetected => write!(f, "tree contains a cycle"),
+        }
+    }
+}
+
+impl error::Error for ProfileError {}
+
+/// A boxed gram distance function: given two grams and a filler value, it returns
+/// how *close* they are (0 to 1) and how they order relative to one another. The
+/// distance functions take any closure of this shape; the box is for keeping
+/// several of them together, as pqgram_distance_ensemble does.
+pub type GramDistanceFn<L> = Box<dyn Fn(&PQGram<L>, &PQGram<L>, &L)->(f64, cmp::Ordering)>;
+
 /// Implement this for a tree to let it be PQGrammed.
 pub trait LabelledTree<L: ValidGramElement> {
     fn label(&self) -> Node<L>;
     fn children(&self) -> Vec<&Self>;
 }
 
-fn _profile_subtree<L, T>(subtree: &T, p: usize, q: usize, ancestors: &mut BDeque<Node<L>>) -> Vec<PQGram<L>>
+/// Profile a subtree, returning the number of nodes in it.
+pub(crate) fn _profile_subtree<L, T, F>(subtree: &T, q: usize, ancestors: &mut BDeque<Node<L>>, emit: &mut F) -> usize
+    where L: ValidGramElement, T: LabelledTree<L>, F: FnMut(PQGram<L>)
+{
+    _emit_node(subtree.label(), subtree.children(), q, ancestors, emit)
+}
+
+/// Profile a node given its label and children, which need not come from a
+/// LabelledTree; this lets containers such as Forest profile under a virtual root.
+/// Returns the grams and the number of nodes, the given one included.
+pub(crate) fn _profile_node<L, T>(label: Node<L>, children: Vec<&T>, q: usize, ancestors: &mut BDeque<Node<L>>) -> (Vec<PQGram<L>>, usize)
     where L: ValidGramElement, T: LabelledTree<L>
 {
-    ancestors.push_back(subtree.label());
+    let mut pqgrams = Vec::<PQGram<L>>::new();
+    let nodes = _emit_node(label, children, q, ancestors, &mut |g| pqgrams.push(g));
+    (pqgrams, nodes)
+}
+
+/// A node whose children are still being profiled, with its ancestors (itself
+/// included) and its current window of siblings.
+struct Frame<'a, L: ValidGramElement, T: 'a> {
+    ancestors: BDeque<Node<L>>,
+    siblings: BDeque<Node<L>>,
+    children: vec::IntoIter<&'a T>,
+}
+
+/// Start on a node: a leaf emits its one all-filler gram, anything else gets a frame.
+fn _enter_node<'a, L, T, F>(ancestors: BDeque<Node<L>>, children: Vec<&'a T>, q: usize, stack: &mut Vec<Frame<'a, L, T>>, emit: &mut F)
+    where L: ValidGramElement, T: LabelledTree<L>, F: FnMut(PQGram<L>)
+{
     let mut siblings = BDeque::<Node<L>>::new(q);
     siblings.fill_with(Node::Filler);
-    let mut pqgrams = Vec::<PQGram<L>>::new();
-    if subtree.children().iter().count() == 0 {
-        pqgrams.push(PQGram::new(ancestors.copy_state(), siblings.copy_state()));
+    if children.is_empty() {
+        emit(PQGram::new(ancestors.copy_state(), siblings.copy_state()));
     } else {
-        for child in subtree.children() {
-            siblings.push_back(child.label());
-            pqgrams.push(PQGram::new(ancestors.copy_state(), siblings.copy_state()));
-            for grandchild in _profile_subtree(child, p, q, &mut ancestors.clone()) {
-                pqgrams.push(grandchild)
-            }
-        }
-        for _ in 0..q-1 {
-            siblings.push_back(Node::Filler);
-            pqgrams.push(PQGram::new(ancestors.copy_state(), siblings.copy_state()))
+        stack.push(Frame{ancestors, siblings, children: children.into_iter()});
+    }
+}
+
+/// Walks the tree with an explicit stack rather than recursion, so that profiling
+/// depth is bounded by the heap and not the thread's stack. Returns the number of
+/// nodes profiled.
+fn _emit_node<L, T, F>(label: Node<L>, children: Vec<&T>, q: usize, ancestors: &mut BDeque<Node<L>>, emit: &mut F) -> usize
+    where L: ValidGramElement, T: LabelledTree<L>, F: FnMut(PQGram<L>)
+{
+    ancestors.push_back(label);
+    let mut stack = Vec::new();
+    let mut nodes = 1;
+    _enter_node(ancestors.clone(), children, q, &mut stack, emit);
+    while let Some(frame) = stack.last_mut() {
+        match frame.children.next() {
+            Some(child) => {
+                let child_label = child.label();
+                frame.siblings.push_back(child_label.clone());
+                emit(PQGram::new(frame.ancestors.copy_state(), frame.siblings.copy_state()));
+                let mut child_ancestors = frame.ancestors.clone();
+                child_ancestors.push_back(child_label);
+                nodes += 1;
+                _enter_node(child_ancestors, child.children(), q, &mut stack, emit);
+            },
+            None => {
+                for _ in 0..q-1 {
+                    frame.siblings.push_back(Node::Filler);
+                    emit(PQGram::new(frame.ancestors.copy_state(), frame.siblings.copy_state()))
+                }
+                stack.pop();
+            },
         }
     }
-    pqgrams
+    nodes
+}
+
+/// Build a PQGram vector profile.
+///
+/// Unsorted profiles come out in a fixed, documented order that callers may rely on:
+/// the tree is walked in preorder, and each node's sibling windows are interleaved
+/// with its children. That is, a node emits the window ending at its first child,
+/// then the whole of that child's subtree, then the window ending at its second
+/// child and that child's subtree, and so on, followed by its q-1 trailing windows.
+/// A leaf emits its single all-filler window. With sort = true the profile is
+/// instead in PQGram order.
+///
+/// Degenerate trees are valid input: a single-node tree yields exactly one gram (its
+/// label under p-1 fillers, with q fillers for siblings), and a p or q larger than the
+/// tree's depth or fan-out simply pads more of each gram with fillers. Panics if p or
+/// q is zero, since such grams carry no anchor or no children; use
+/// pqgram_profile_checked to get an error instead.
+pub fn pqgram_profile<L, T>(tree: T, p: usize, q: usize, sort: bool) -> Profile<L>
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    pqgram_profile_ref(&tree, p, q, sort)
 }
 
-/// Build a PQGram vector profile
-pub fn pqgram_profile<L, T>(tree: T, p: usize, q: usize, sort: bool) -> Vec<PQGram<L>>
+/// As pqgram_profile, but borrows the tree, e.g. to profile a subtree in place.
+pub fn pqgram_profile_ref<L, T>(tree: &T, p: usize, q: usize, sort: bool) -> Profile<L>
     where L: ValidGramElement, T: LabelledTree<L>
 {
+    assert!(p > 0 && q > 0, "p and q must both be at least 1, got p = {}, q = {}", p, q);
+    let mut ancestors = BDeque::<Node<L>>::new(p);
+    ancestors.fill_with(Node::Filler);
+    let mut grams = Vec::new();
+    let nodes = _profile_subtree(tree, q, &mut ancestors, &mut |g| grams.push(g));
+    if sort { sort_profile(&mut grams) }
+    Profile::from_profiler(grams, p, q, sort, nodes)
+}
+
+/// Profile a tree without collecting it, handing each gram to emit as it is made, in
+/// the unsorted order documented on pqgram_profile. Use this to stream grams into a
+/// database, hasher or socket without holding the whole profile in memory. Returns
+/// the number of nodes in the tree. Panics if p or q is zero.
+pub fn pqgram_profile_each<L, T, F>(tree: &T, p: usize, q: usize, mut emit: F) -> usize
+    where L: ValidGramElement, T: LabelledTree<L>, F: FnMut(PQGram<L>)
+{
+    assert!(p > 0 && q > 0, "p and q must both be at least 1, got p = {}, q = {}", p, q);
     let mut ancestors = BDeque::<Node<L>>::new(p);
     ancestors.fill_with(Node::Filler);
-    let mut prof = _profile_subtree(&tree, p, q, &mut ancestors);
-    if sort { prof.sort() }
-    prof
+    _profile_subtree(tree, q, &mut ancestors, &mut emit)
+}
+
+/// Profile a tree with set semantics: each distinct gram once, in PQGram order.
+/// Duplicates are dropped as the grams are made rather than after collecting them,
+/// which saves memory for set-based measures that ignore multiplicity. Panics if p or
+/// q is zero.
+pub fn pqgram_profile_distinct<L, T>(tree: &T, p: usize, q: usize) -> Profile<L>
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    let mut distinct = BTreeSet::new();
+    let nodes = pqgram_profile_each(tree, p, q, |g| { distinct.insert(g); });
+    Profile::from_parts(distinct.into_iter().collect(), p, q, true).with_node_count(nodes)
+}
+
+/// Sort a profile in place, as pqgram_profile does when asked to. Equal grams are
+/// indistinguishable, so an unstable sort is used; sorting is often the most
+/// expensive part of profiling a large tree, so callers that profile unsorted can
+/// choose when to pay for it.
+pub fn sort_profile<L: ValidGramElement>(profile: &mut [PQGram<L>]) {
+    profile.sort_unstable()
+}
+
+/// As sort_profile, but sorts in parallel on the rayon thread pool. Worthwhile for
+/// profiles of millions of grams.
+#[cfg(feature = "rayon")]
+pub fn par_sort_profile<L: ValidGramElement + Send>(profile: &mut [PQGram<L>]) {
+    use rayon::slice::ParallelSliceMut;
+    profile.par_sort_unstable()
+}
+
+/// Profile a tree that may be missing, as happens in pipelines with absent documents.
+/// A missing tree has an empty profile; see pqgram_distance_with_fn for how empty
+/// profiles compare.
+pub fn pqgram_profile_opt<L, T>(tree: Option<T>, p: usize, q: usize, sort: bool) -> Profile<L>
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    match tree {
+        Some(t) => pqgram_profile(t, p, q, sort),
+        None => Profile::from_parts(Vec::new(), p, q, true).with_node_count(0),
+    }
+}
+
+/// Count the grams a subtree will contribute to a profile, without building them.
+/// Returns None if the count overflows. Walks with an explicit stack, like
+/// _emit_node, so that it is safe on trees of any depth.
+fn _count_subtree<L, T>(subtree: &T, q: usize) -> Option<usize>
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    let mut count: usize = 0;
+    let mut pending = vec![subtree];
+    while let Some(node) = pending.pop() {
+        let children = node.children();
+        let grams = if children.is_empty() { 1 } else { children.len().checked_add(q)?.checked_sub(1)? };
+        count = count.checked_add(grams)?;
+        pending.extend(children);
+    }
+    Some(count)
+}
+
+/// The number of grams pqgram_profile would produce for a tree, from one traversal
+/// that builds nothing: one per leaf, plus fan-out + q - 1 per inner node. Despite
+/// the name the count is exact, and since it does not depend on p only q is taken.
+/// Use it to budget memory or choose between exact and sampled comparison before
+/// profiling. The traversal is iterative, so any tree is safe to size. Saturates at
+/// usize::MAX.
+pub fn estimate_profile_len<L, T>(tree: &T, q: usize) -> usize
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    _count_subtree(tree, q).unwrap_or(usize::MAX)
+}
+
+/// As pqgram_profile_ref, but first sizes the profile with checked arithmetic and
+/// refuses to build it if it would hold more than max_grams grams, or if the memory it
+/// needs cannot be represented in a usize. Zero p or q is reported as an error. Use
+/// this on untrusted input, where a hostile tree could otherwise make profiling
+/// allocate without bound.
+pub fn pqgram_profile_checked<L, T>(tree: &T, p: usize, q: usize, sort: bool, max_grams: usize) -> Result<Profile<L>, ProfileError>
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    if p == 0 || q == 0 {
+        return Err(ProfileError::InvalidParameters{p, q})
+    }
+    let grams = _count_subtree(tree, q).ok_or(ProfileError::SizeOverflow)?;
+    p.checked_add(q)
+     .and_then(|width| width.checked_mul(mem::size_of::<Node<L>>()))
+     .and_then(|gram_size| gram_size.checked_mul(grams))
+     .ok_or(ProfileError::SizeOverflow)?;
+    if grams > max_grams {
+        return Err(ProfileError::TooManyGrams{grams, max_grams})
+    }
+    Ok(pqgram_profile_ref(tree, p, q, sort))
+}
+
+/// Whether a subtree is at most max_depth levels deep. Walks with an explicit stack of
+/// nodes and their depths, and stops at the first node past the limit, so this is
+/// safe on trees of any depth and whatever the limit.
+fn _within_depth<L, T>(subtree: &T, max_depth: usize) -> bool
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    let mut pending = vec![(subtree, 1)];
+    while let Some((node, depth)) = pending.pop() {
+        if depth > max_depth {
+            return false
+        }
+        pending.extend(node.children().into_iter().map(|c| (c, depth + 1)));
+    }
+    true
+}
+
+/// As pqgram_profile_ref, but returns DepthLimitExceeded instead of profiling a tree
+/// nested more than max_depth levels deep (a single node is one level). Profiling
+/// itself is iterative, but services handling untrusted trees can use this to
+/// reject pathological input, and to protect other code that does recurse, such as
+/// LabelledTree implementations or Tree's Clone.
+pub fn pqgram_profile_depth_limited<L, T>(tree: &T, p: usize, q: usize, sort: bool, max_depth: usize) -> Result<Profile<L>, ProfileError>
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    if p == 0 || q == 0 {
+        return Err(ProfileError::InvalidParameters{p, q})
+    }
+    if !_within_depth(tree, max_depth) {
+        return Err(ProfileError::DepthLimitExceeded{max_depth})
+    }
+    Ok(pqgram_profile_ref(tree, p, q, sort))
+}
+
+/// Whether any node below subtree is also one of its own ancestors, comparing nodes
+/// by address. Shared subtrees that are not their own ancestors are fine. Walks with
+/// an explicit stack, keeping the current path of ancestors in a set, so it is safe
+/// on deep trees and linear in their size.
+fn _has_cycle<L, T>(subtree: &T) -> bool
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    let mut path: HashSet<*const T> = HashSet::new();
+    path.insert(subtree);
+    let mut stack = vec![(subtree as *const T, subtree.children().into_iter())];
+    while let Some(&mut (node, ref mut children)) = stack.last_mut() {
+        match children.next() {
+            Some(child) => {
+                if !path.insert(child) {
+                    return true
+                }
+                stack.push((child, child.children().into_iter()));
+            },
+            None => {
+                path.remove(&node);
+                stack.pop();
+            },
+        }
+    }
+    false
+}
+
+/// As pqgram_profile_ref, but returns CycleDetected if a node is reachable from its
+/// own children rather than recursing forever. This can only happen with a buggy
+/// LabelledTree implementation, e.g. one over an arena or graph whose children()
+/// can return an ancestor; use it to keep such adapter bugs from taking a service
+/// down. Nodes are compared by address, so children() must return references to
+/// stable nodes rather than to freshly built ones.
+pub fn pqgram_profile_acyclic<L, T>(tree: &T, p: usize, q: usize, sort: bool) -> Result<Profile<L>, ProfileError>
+    where L: ValidGramElement, T: LabelledTree<L>
+{
+    if p == 0 || q == 0 {
+        return Err(ProfileError::InvalidParameters{p, q})
+    }
+    if _has_cycle(tree) {
+        return Err(ProfileError::CycleDetected)
+    }
+    Ok(pqgram_profile_ref(tree, p, q, sort))
 }
 
 /// PQGrams are nested structures of ancestors and siblings, but their intended use
 /// is usually as flat vectors of constant length. This converts all PQGram elements
 /// in a profile into flat vectors.
-pub fn flatten_profile<L: ValidGramElement>(profile: &Vec<PQGram<L>>, filler_as: L) -> Vec<Vec<L>> {
-    profile.into_iter()
+pub fn flatten_profile<L: ValidGramElement>(profile: &[PQGram<L>], filler_as: L) -> Vec<Vec<L>> {
+    profile.iter()
            .map(|gram| gram.concat(filler_as.clone()))
            .collect()
 }
 
-/// Expects that the pqgram profiles be sorted. distance_function should return how *close* two grams are,
-/// as a float between 0 and 1.
-pub fn pqgram_profile_intersection<L, T>(left: &Vec<PQGram<L>>, right: &Vec<PQGram<L>>, alt_filler_value: Option<L>, distance_function: Box<Fn(&PQGram<L>, &PQGram<L>, L)->(f64, cmp::Ordering)>) -> f64
-    where L: ValidGramElement, T: LabelledTree<L>
+/// Split a profile into the grams anchored at each distinct label, keeping profile
+/// order within each group. Useful for per-label statistics and reports.
+pub fn group_by_anchor<L: ValidGramElement>(profile: &[PQGram<L>]) -> BTreeMap<Node<L>, Vec<&PQGram<L>>> {
+    let mut groups = BTreeMap::new();
+    for gram in profile {
+        groups.entry(gram.anchor().clone()).or_insert_with(Vec::new).push(gram);
+    }
+    groups
+}
+
+/// Namespaced labels, as produced by concat_profiles.
+impl<L: ValidGramElement> ValidGramElement for (usize, L) {}
+
+/// Combine the profiles of several independent trees (e.g. the head and body of a
+/// document) into one profile. Each label is namespaced by the index of the profile
+/// it came from, so grams from different fields never match one another, and a
+/// distance between two combined profiles is a distance over all their fields.
+/// Panics if no profiles are given, or if they were built with different p or q.
+pub fn concat_profiles<L: ValidGramElement>(profiles: &[&Profile<L>], sort: bool) -> Profile<(usize, L)> {
+    let (p, q) = profiles.first().map(|f| (f.p, f.q)).expect("concat_profiles needs at least one profile");
+    for profile in profiles {
+        _check_compatible(profiles[0], profile);
+    }
+    let namespace = |i: usize, nodes: &[Node<L>]| -> Vec<Node<(usize, L)>> {
+        nodes.iter().map(|n| match *n {
+            Node::Label(ref l) => Node::Label((i, l.clone())),
+            Node::Filler => Node::Filler,
+        }).collect()
+    };
+    let mut combined: Vec<PQGram<(usize, L)>> = profiles.iter().enumerate().flat_map(|(i, profile)| {
+        profile.iter().map(move |gram| PQGram::new(namespace(i, &gram.ancestors), namespace(i, &gram.siblings)))
+    }).collect();
+    if sort { sort_profile(&mut combined) }
+    let node_count = profiles.iter().map(|profile| profile.node_count).sum();
+    Profile::from_parts(combined, p, q, sort).with_node_count_opt(node_count)
+}
+
+/// Limits on how much work a distance computation may do.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct Budget {
+    /// Stop after this many gram comparisons.
+    pub max_comparisons: Option<usize>,
+    /// Stop once this instant has passed. Checked every DEADLINE_CHECK_INTERVAL comparisons.
+    pub deadline: Option<Instant>,
+}
+
+/// How often, in gram comparisons, a Budget's deadline is checked.
+pub const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
+/// The state of an intersection walk when it finished or ran out of budget.
+struct Walk {
+    intersection: f64,
+    i: usize,
+    j: usize,
+    complete: bool,
+}
+
+/// Compensated (Kahan) summation, so that weighted kernels adding small fractional
+/// scores over millions of grams don't lose precision to rounding.
+#[derive(Default)]
+struct KahanSum {
+    sum: f64,
+    compensation: f64,
+}
+
+impl KahanSum {
+    fn add(&mut self, value: f64) {
+        let y = value - self.compensation;
+        let t = self.sum + y;
+        self.compensation = (t - self.sum) - y;
+        self.sum = t;
+    }
+
+    fn total(&self) -> f64 {
+        self.sum
+    }
+}
+
+/// How many grams at the start of a sorted profile are equal to the first.
+fn _run_length<L: ValidGramElement>(grams: &[PQGram<L>]) -> usize {
+    grams.iter().take_while(|g| **g == grams[0]).count()
+}
+
+fn _walk_intersection<L, F>(left: &[PQGram<L>], right: &[PQGram<L>], alt_filler_value: Option<L>, mut distance_function: F, budget: Budget) -> Walk
+    where L: ValidGramElement, F: FnMut(&PQGram<L>, &PQGram<L>, &L) -> (f64, cmp::Ordering)
 {
-    let mut intersection: f64 = 0.;
+    let mut intersection = KahanSum::default();
     let mut i: usize = 0;
     let mut j: usize = 0;
+    let mut comparisons: usize = 0;
     let maxi = left.len();
     let maxj = right.len();
-    let filler = if let Some(l) = alt_filler_value { l } else { L::default() };
+    let filler = alt_filler_value.unwrap_or_default();
+    // Runs of identical grams are compared rather than single grams, so that repeats
+    // are credited by the smaller multiplicity and no gram is credited twice. Each
+    // side's run is measured once when that side reaches it, keeping the walk linear.
+    let mut irun = if maxi > 0 { _run_length(left) } else { 0 };
+    let mut jrun = if maxj > 0 { _run_length(right) } else { 0 };
     while i < maxi && j < maxj {
-        let ref ig = left[i];
-        let ref jg = right[j];
-        let (distance, order) = distance_function(&ig, &jg, filler.clone());
-        intersection += distance;
-        match order {
-            cmp::Ordering::Equal => {
-                i += 1;
-                j += 1;
-            },
-            cmp::Ordering::Less => i += 1,
-            cmp::Ordering::Greater => j += 1,
+        if budget.max_comparisons.is_some_and(|max| comparisons >= max) ||
+           (comparisons.is_multiple_of(DEADLINE_CHECK_INTERVAL) && budget.deadline.is_some_and(|d| Instant::now() >= d)) {
+            return Walk{intersection: intersection.total(), i, j, complete: false}
+        }
+        comparisons += 1;
+        let (distance, order) = distance_function(&left[i], &right[j], &filler);
+        intersection.add(distance * irun.min(jrun) as f64);
+        if order != cmp::Ordering::Greater {
+            i += irun;
+            if i < maxi { irun = _run_length(&left[i..]) }
+        }
+        if order != cmp::Ordering::Less {
+            j += jrun;
+            if j < maxj { jrun = _run_length(&right[j..]) }
         }
     }
-    intersection
+    Walk{intersection: intersection.total(), i, j, complete: true}
 }
 
-/// This is the default gram edit distance function. It simply concatenates ancestor + sibling
-/// vecs for each PQGram, then returns (1, Equal) if they are identical, and (0, Less || Greater)
-/// if they are different. There are no intermediate values. This logic is borrowed from PyGram,
-/// and more meaningful results might be possible with more accurate measures of gram-edit distance.
-pub fn default_gram_edit_distance<'a, L: 'a>(left: &PQGram<L>, right: &PQGram<L>, filler_value: L) -> (f64, cmp::Ordering)
-    where L: ValidGramElement
+/// Apply f to every label in a profile, keeping fillers as they are. Labels that f
+/// maps together become equal, so the result is re-sorted if asked, since mapping
+/// generally does not preserve order.
+pub fn map_profile_labels<L, M, F>(profile: &[PQGram<L>], f: F, sort: bool) -> Vec<PQGram<M>>
+    where L: ValidGramElement, M: ValidGramElement, F: Fn(&L) -> M
 {
-    let iter_compare = left.concat(filler_value.clone())
-                           .into_iter()
-                           .zip(right.concat(filler_value.clone()).into_iter())
-                           .map(|(l, r)| l.partial_cmp(&r)
-                                        .expect(format!("Ordering not possible for l, r: {:?}, {:?}", l, r).as_ref()));
-    for ordering in iter_compare {
-        match ordering {
-            cmp::Ordering::Equal => continue,
-            _ => return (0., ordering)
-        }
+    let map_nodes = |nodes: &[Node<L>]| -> Vec<Node<M>> {
+        nodes.iter().map(|n| match *n {
+            Node::Label(ref l) => Node::Label(f(l)),
+            Node::Filler => Node::Filler,
+        }).collect()
     };
-    (1., cmp::Ordering::Equal)
+    let mut mapped: Vec<PQGram<M>> = profile.iter()
+                                            .map(|g| PQGram::new(map_nodes(&g.ancestors), map_nodes(&g.siblings)))
+                                            .collect();
+    if sort { sort_profile(&mut mapped) }
+    mapped
 }
 
-/// Given two sorted PQGram profiles, return a float value representing their distance, using
-/// the provided distance function to provide a 0..1 measure of similarity between PQGrams.
-/// If alt_filler_value is None, then the Default for type L is used to fill in Node::Filler
-/// elements in the PQGrams before ordering. When the Default for L is a value that also occurs
-/// in a valid tree (often the case!), you should provide an Value here that does not occur
-/// in the tree.
-pub fn pqgram_distance_with_fn<L: 'static, T>(left: &Vec<PQGram<L>>, right: &Vec<PQGram<L>>, alt_filler_value: Option<L>, distance_function: Box<Fn(&PQGram<L>, &PQGram<L>, L)->(f64, cmp::Ordering)>) -> f64
-    where L: ValidGramElement, T: LabelledTree<L>
+/// As pqgram_distance, but labels are passed through normalize (e.g. case-folding or
+/// stripping digits) just before comparison, so normalizations can be tried out on
+/// stored profiles without re-profiling the trees they came from. Panics if the
+/// profiles were built with different p or q.
+pub fn pqgram_distance_normalized<L, F>(left: &Profile<L>, right: &Profile<L>, normalize: F) -> f64
+    where L: ValidGramElement, F: Fn(&L) -> L
+{
+    _check_compatible(left, right);
+    let left = map_profile_labels(left, &normalize, true);
+    let right = map_profile_labels(right, &normalize, true);
+    _distance_with_fn(&left, &right, None, default_gram_edit_distance)
+}
+
+/// As pqgram_distance, but penalized for comparing trees of very different sizes:
+/// the similarity, one minus the distance, is scaled by the smaller tree's node count
+/// over the larger's. A small tree that is a subtree of a large one shares all its
+/// grams with it, which the plain distance can make look closer than it is. Two
+/// empty trees are at distance 0. Panics if the profiles were built with different p
+/// or q, or if either has no node count; see Profile::with_node_count.
+pub fn pqgram_distance_size_aware<L>(left: &Profile<L>, right: &Profile<L>) -> f64
+    where L: ValidGramElement
+{
+    let node_count = |profile: &Profile<L>| profile.node_count.expect("size-aware distance needs profiles with node counts");
+    let (left_nodes, right_nodes) = (node_count(left), node_count(right));
+    let distance = pqgram_distance(left, right);
+    let larger = left_nodes.max(right_nodes);
+    if larger == 0 { return distance }
+    let ratio = left_nodes.min(right_nodes) as f64 / larger as f64;
+    1. - (1. - distance) * ratio
+}
+
+/// Expects that the pqgram profiles be sorted. distance_function should return how *close* two grams are,
+/// as a float between 0 and 1. Profiles are bags: a gram occurring m times on one side and n on the
+/// other contributes min(m, n) times its score.
+pub fn pqgram_profile_intersection<L, F>(left: &[PQGram<L>], right: &[PQGram<L>], alt_filler_value: Option<L>, distance_function: F) -> f64
+    where L: ValidGramElement, F: FnMut(&PQGram<L>, &PQGram<L>, &L) -> (f64, cmp::Ordering)
+{
+    _walk_intersection(left, right, alt_filler_value, distance_function, Budget::default()).intersection
+}
+
+/// This is the default gram edit distance function. It compares the ancestor then sibling
+/// nodes of each PQGram, in the same order profiles are sorted by, then returns (1, Equal) if
+/// they are identical, and (0, Less || Greater) if they are different. There are no intermediate
+/// values. This logic is borrowed from PyGram, and more meaningful results might be possible
+/// with more accurate measures of gram-edit distance. Fillers are compared as nodes, so they
+/// never equal a label, even one equal to L's default; filler_value is ignored.
+pub fn default_gram_edit_distance<L>(left: &PQGram<L>, right: &PQGram<L>, _filler_value: &L) -> (f64, cmp::Ordering)
+    where L: ValidGramElement
+{
+    match left.cmp(right) {
+        cmp::Ordering::Equal => (1., cmp::Ordering::Equal),
+        ordering => (0., ordering),
+    }
+}
+
+fn _distance_with_fn<L, F>(left: &[PQGram<L>], right: &[PQGram<L>], alt_filler_value: Option<L>, distance_function: F) -> f64
+    where L: ValidGramElement, F: FnMut(&PQGram<L>, &PQGram<L>, &L) -> (f64, cmp::Ordering)
 {
     let union = (left.len() + right.len()) as f64;  // TODO: this is copied from the Python, but surely it should be the length of the set-union?
-    let intersection: f64 = pqgram_profile_intersection::<L,T>(left, right, alt_filler_value, distance_function);
-    return 1. - 2. * (intersection / union)
+    if union == 0. { return 0. }
+    let intersection: f64 = pqgram_profile_intersection(left, right, alt_filler_value, distance_function);
+    1. - 2. * (intersection / union)
+}
+
+/// Grams built with different p or q never match, so comparing such profiles gives a
+/// meaningless distance rather than an obviously wrong one.
+pub(crate) fn _check_compatible<L: ValidGramElement>(left: &Profile<L>, right: &Profile<L>) {
+    assert!(left.p == right.p && left.q == right.q,
+            "cannot compare profiles built with different parameters: p = {}, q = {} and p = {}, q = {}",
+            left.p, left.q, right.p, right.q);
+}
+
+/// Given two PQGram profiles, return a float value representing their distance, using
+/// the provided distance function to provide a 0..1 measure of similarity between PQGrams.
+/// alt_filler_value is passed to the distance function, for functions that compare labels
+/// and need a value to stand in for Node::Filler; if None, the Default for type L is used.
+/// Such a function should order grams as PQGram's Ord does, fillers first, or matches will
+/// be missed, and the stand-in should be a value that does not occur in the trees.
+///
+/// Unsorted profiles are sorted (a copy, not in place) before comparison. Panics if the
+/// profiles were built with different p or q.
+///
+/// An empty profile stands for an empty tree: two empty profiles are identical
+/// (distance 0), and an empty profile is maximally distant (1) from any other.
+///
+/// distance_function is called once per comparison with the filler borrowed, and may
+/// be any closure, including one that keeps state, e.g. to count matching grams.
+pub fn pqgram_distance_with_fn<L, F>(left: &Profile<L>, right: &Profile<L>, alt_filler_value: Option<L>, distance_function: F) -> f64
+    where L: ValidGramElement, F: FnMut(&PQGram<L>, &PQGram<L>, &L) -> (f64, cmp::Ordering)
+{
+    _check_compatible(left, right);
+    _distance_with_fn(&left.sorted_grams(), &right.sorted_grams(), alt_filler_value, distance_function)
 }
 
 /// Calculates PQGram distance between two profiles, using the default_gram_edit_distance function.
-/// All notes for pqgram_distance_with_fn apply here, particularly with respect to alt_filler_value!
-pub fn pqgram_distance<L: 'static, T>(left: &Vec<PQGram<L>>, right: &Vec<PQGram<L>>, alt_filler_value: Option<L>) -> f64
+/// Fillers are compared as nodes, so no filler value is needed, even for trees whose labels
+/// include L's default. The other notes for pqgram_distance_with_fn apply here.
+pub fn pqgram_distance<L>(left: &Profile<L>, right: &Profile<L>) -> f64
+    where L: ValidGramElement
+{
+    pqgram_distance_with_fn(left, right, None, default_gram_edit_distance)
+}
+
+/// Distance between a tree and a stored profile, for querying against precomputed
+/// corpora. The tree is profiled with the p and q the stored profile was built with,
+/// but its grams are never collected: each is matched against counts borrowed from
+/// the stored profile as it is made, then dropped, so the stored profile need not be
+/// sorted either. Gives the same distance as profiling the tree and calling
+/// pqgram_distance.
+pub fn pqgram_distance_to_profile<L, T>(tree: &T, profile: &Profile<L>) -> f64
     where L: ValidGramElement, T: LabelledTree<L>
 {
-    pqgram_distance_with_fn::<L,T>(left, right, alt_filler_value, Box::new(default_gram_edit_distance))
+    let mut counts: BTreeMap<&PQGram<L>, usize> = BTreeMap::new();
+    for gram in profile {
+        *counts.entry(gram).or_insert(0) += 1;
+    }
+    let mut tree_len = 0;
+    let mut intersection = 0;
+    pqgram_profile_each(tree, profile.p, profile.q, |g| {
+        tree_len += 1;
+        if let Some(count) = counts.get_mut(&g) {
+            if *count > 0 {
+                *count -= 1;
+                intersection += 1;
+            }
+        }
+    });
+    let union = (tree_len + profile.len()) as f64;
+    if union == 0. { return 0. }
+    1. - 2. * (intersection as f64 / union)
+}
+
+/// As pqgram_distance, but only grams whose anchor satisfies the predicate are
+/// compared, e.g. to ask how different just the `form`-anchored grams of two pages
+/// are. Unsorted profiles are sorted first, as for pqgram_distance, and filtering
+/// keeps that order. Panics if the profiles were built with different p or q.
+pub fn pqgram_distance_by_anchor<L, P>(left: &Profile<L>, right: &Profile<L>, predicate: P) -> f64
+    where L: ValidGramElement, P: Fn(&Node<L>) -> bool
+{
+    _check_compatible(left, right);
+    let left: Vec<PQGram<L>> = left.sorted_grams().iter().filter(|g| predicate(g.anchor())).cloned().collect();
+    let right: Vec<PQGram<L>> = right.sorted_grams().iter().filter(|g| predicate(g.anchor())).cloned().collect();
+    _distance_with_fn(&left, &right, None, default_gram_edit_distance)
+}
+
+/// Run several weighted gram distance functions over the same pair of profiles,
+/// returning the weighted sum of their distances along with each component's distance,
+/// in the order given. Weights that sum to 1 keep the combined score between 0 and 1.
+/// Unsorted profiles are sorted once, up front. Panics if the profiles were built with
+/// different p or q.
+pub fn pqgram_distance_ensemble<L>(left: &Profile<L>, right: &Profile<L>, alt_filler_value: Option<L>, components: Vec<(f64, GramDistanceFn<L>)>) -> (f64, Vec<f64>)
+    where L: ValidGramElement
+{
+    _check_compatible(left, right);
+    let (left, right) = (left.sorted_grams(), right.sorted_grams());
+    let mut combined = 0.;
+    let mut scores = Vec::with_capacity(components.len());
+    for (weight, distance_function) in components {
+        let score = _distance_with_fn(&left, &right, alt_filler_value.clone(), distance_function);
+        combined += weight * score;
+        scores.push(score);
+    }
+    (combined, scores)
+}
+
+/// A pq-gram distance held as integer gram counts rather than a float, so that it is
+/// bit-identical however and wherever it was computed, and can be audited or
+/// summed across shards without rounding. The distance is
+/// (total - 2 * shared) / total, or 0 when both profiles were empty.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ExactDistanceData"))]
+pub struct ExactDistance {
+    shared: usize,
+    total: usize,
+}
+
+/// ExactDistance as it is deserialized, before its counts are checked.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct ExactDistanceData {
+    shared: usize,
+    total: usize,
+}
+
+#[cfg(feature = "serde")]
+impl ::std::convert::TryFrom<ExactDistanceData> for ExactDistance {
+    type Error = String;
+
+    fn try_from(data: ExactDistanceData) -> Result<ExactDistance, String> {
+        ExactDistance::_checked(data.shared, data.total)
+    }
+}
+
+impl ExactDistance {
+    /// Wrap counts from pqgram_distance_exact, e.g. ones read back from storage.
+    /// Panics if more grams are shared than half the total, which no two profiles
+    /// can give.
+    pub fn new(shared: usize, total: usize) -> ExactDistance {
+        ExactDistance::_checked(shared, total).unwrap_or_else(|message| panic!("{}", message))
+    }
+
+    fn _checked(shared: usize, total: usize) -> Result<ExactDistance, String> {
+        match shared.checked_mul(2) {
+            Some(both) if both <= total => Ok(ExactDistance{shared, total}),
+            _ => Err(format!("{} shared grams is more than half of {} in total", shared, total)),
+        }
+    }
+
+    /// Grams present in both profiles, counting repeats.
+    pub fn shared(&self) -> usize {
+        self.shared
+    }
+
+    /// The two profiles' lengths added together.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn numerator(&self) -> usize {
+        self.total - 2 * self.shared
+    }
+
+    pub fn denominator(&self) -> usize {
+        self.total.max(1)
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.numerator() as f64 / self.denominator() as f64
+    }
+}
+
+impl PartialOrd for ExactDistance {
+    fn partial_cmp(&self, other: &ExactDistance) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Orders by the distance itself, comparing fractions exactly.
+impl Ord for ExactDistance {
+    fn cmp(&self, other: &ExactDistance) -> cmp::Ordering {
+        let left = self.numerator() as u128 * other.denominator() as u128;
+        let right = other.numerator() as u128 * self.denominator() as u128;
+        left.cmp(&right).then(self.total.cmp(&other.total))
+    }
+}
+
+/// The pq-gram distance between two profiles as exact integer counts; see
+/// ExactDistance. Grams are compared whole, so fillers never collide with labels.
+/// Unsorted profiles are sorted first. Panics if the profiles were built with
+/// different p or q.
+pub fn pqgram_distance_exact<L: ValidGramElement>(left: &Profile<L>, right: &Profile<L>) -> ExactDistance {
+    _check_compatible(left, right);
+    let (left, right) = (left.sorted_grams(), right.sorted_grams());
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < left.len() && j < right.len() {
+        match left[i].cmp(&right[j]) {
+            cmp::Ordering::Equal => { shared += 1; i += 1; j += 1 },
+            cmp::Ordering::Less => i += 1,
+            cmp::Ordering::Greater => j += 1,
+        }
+    }
+    ExactDistance{shared, total: left.len() + right.len()}
+}
+
+/// Result of a distance computation that may have run out of budget.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum BudgetedDistance {
+    /// The computation finished; this is the same value pqgram_distance_with_fn gives.
+    Exact(f64),
+    /// The budget ran out. The estimate is the distance over the grams merged so far,
+    /// or 1.0 if none were.
+    Partial(f64),
+}
+
+/// As pqgram_distance_with_fn, but stops early once the budget is spent, returning an
+/// estimate instead, so latency-sensitive callers can bound worst-case inputs.
+/// Sorting an unsorted profile happens before the budget starts to count.
+pub fn pqgram_distance_with_budget<L, F>(left: &Profile<L>, right: &Profile<L>, alt_filler_value: Option<L>, distance_function: F, budget: Budget) -> BudgetedDistance
+    where L: ValidGramElement, F: FnMut(&PQGram<L>, &PQGram<L>, &L) -> (f64, cmp::Ordering)
+{
+    _check_compatible(left, right);
+    let (left, right) = (left.sorted_grams(), right.sorted_grams());
+    let walk = _walk_intersection(&left, &right, alt_filler_value, distance_function, budget);
+    let seen = if walk.complete { left.len() + right.len() } else { walk.i + walk.j };
+    let distance = if seen == 0 {
+        if walk.complete { 0. } else { 1. }
+    } else {
+        1. - 2. * (walk.intersection / seen as f64)
+    };
+    if walk.complete { BudgetedDistance::Exact(distance) } else { BudgetedDistance::Partial(distance) }
 }
@@ -0,0 +1,241 @@
+use std::error;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+use pqgrams::{Node, PQGram, Profile, ValidGramElement};
+
+/// The filler label PyGram uses.
+pub const PYGRAM_FILLER: &str = "*";
+
+/// Rebuild a profile from flat grams, as produced by flatten_profile or PyGram. The
+/// first p labels of each gram are its ancestors and the rest its siblings; labels
+/// equal to filler_as become Node::Filler.
+pub fn unflatten_profile<L: ValidGramElement>(flat: &[Vec<L>], p: usize, filler_as: &L) -> Vec<PQGram<L>> {
+    let to_node = |l: &L| if l == filler_as { Node::Filler } else { Node::Label(l.clone()) };
+    flat.iter().map(|gram| {
+        let (ps, qs) = gram.split_at(p.min(gram.len()));
+        PQGram::new(ps.iter().map(&to_node).collect(), qs.iter().map(&to_node).collect())
+    }).collect()
+}
+
+/// Write a string profile in the flat form used by PyGram: a JSON array of grams,
+/// each an array of p ancestor labels then q sibling labels, with "*" for filler.
+pub fn profile_to_pygram_json(profile: &[PQGram<String>]) -> String {
+    let mut out = String::from("[");
+    for (i, gram) in profile.iter().enumerate() {
+        if i > 0 { out.push_str(", ") }
+        out.push('[');
+        for (j, label) in gram.concat(PYGRAM_FILLER.to_string()).iter().enumerate() {
+            if j > 0 { out.push_str(", ") }
+            write_json_string(&mut out, label);
+        }
+        out.push(']');
+    }
+    out.push(']');
+    out
+}
+
+pub(crate) fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// An error reading PyGram JSON, with the byte offset at which it occurred.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct PyGramError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PyGramError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid PyGram JSON at byte {}: {}", self.position, self.message)
+    }
+}
+
+impl error::Error for PyGramError {}
+
+struct JsonReader<'a> {
+    src: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> JsonReader<'a> {
+    fn position(&mut self) -> usize {
+        self.chars.peek().map(|&(i, _)| i).unwrap_or_else(|| self.src.len())
+    }
+
+    fn error<T>(&mut self, message: &str) -> Result<T, PyGramError> {
+        let position = self.position();
+        Err(PyGramError{position, message: message.to_string()})
+    }
+
+    /// Skip the whitespace JSON allows between tokens, which is narrower than Unicode's.
+    fn skip_whitespace(&mut self) {
+        while let Some(&(_, c)) = self.chars.peek() {
+            if !matches!(c, ' ' | '\t' | '\r' | '\n') { break }
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, want: char) -> Result<(), PyGramError> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some(&(_, c)) if c == want => { self.chars.next(); Ok(()) },
+            _ => self.error(&format!("expected '{}'", want)),
+        }
+    }
+
+    /// Parse a comma-separated array, calling item for each element.
+    fn array<T, F>(&mut self, mut item: F) -> Result<Vec<T>, PyGramError>
+        where F: FnMut(&mut JsonReader<'a>) -> Result<T, PyGramError>
+    {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if let Some(&(_, ']')) = self.chars.peek() {
+            self.chars.next();
+            return Ok(items)
+        }
+        loop {
+            items.push(item(self)?);
+            self.skip_whitespace();
+            match self.chars.peek().map(|&(_, c)| c) {
+                Some(',') => { self.chars.next(); },
+                Some(']') => { self.chars.next(); return Ok(items) },
+                _ => return self.error("expected ',' or ']'"),
+            }
+        }
+    }
+
+    /// Read the four hex digits of a \u escape.
+    fn hex4(&mut self) -> Result<u32, PyGramError> {
+        let hex: String = (0..4).filter_map(|_| self.chars.next().map(|(_, c)| c)).collect();
+        // from_str_radix would also take a sign, as in \u+041.
+        if hex.len() != 4 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return self.error("invalid \\u escape")
+        }
+        Ok(u32::from_str_radix(&hex, 16).expect("Four hex digits always parse"))
+    }
+
+    fn string(&mut self) -> Result<String, PyGramError> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(s),
+                Some((_, '\\')) => match self.chars.next() {
+                    Some((_, '"')) => s.push('"'),
+                    Some((_, '\\')) => s.push('\\'),
+                    Some((_, '/')) => s.push('/'),
+                    Some((_, 'b')) => s.push('\u{8}'),
+                    Some((_, 'f')) => s.push('\u{c}'),
+                    Some((_, 'n')) => s.push('\n'),
+                    Some((_, 'r')) => s.push('\r'),
+                    Some((_, 't')) => s.push('\t'),
+                    Some((_, 'u')) => {
+                        let unit = self.hex4()?;
+                        // Characters outside the BMP are escaped as a UTF-16 surrogate
+                        // pair, which Python's json module does by default.
+                        let code = if (0xD800..0xDC00).contains(&unit) {
+                            match (self.chars.next(), self.chars.next()) {
+                                (Some((_, '\\')), Some((_, 'u'))) => {},
+                                _ => return self.error("unpaired surrogate in \\u escape"),
+                            }
+                            let low = self.hex4()?;
+                            if !(0xDC00..0xE000).contains(&low) {
+                                return self.error("unpaired surrogate in \\u escape")
+                            }
+                            0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                        } else {
+                            unit
+                        };
+                        match ::std::char::from_u32(code) {
+                            Some(c) => s.push(c),
+                            None => return self.error("invalid \\u escape"),
+                        }
+                    },
+                    _ => return self.error("invalid escape"),
+                },
+                Some((_, c)) => s.push(c),
+                None => return self.error("unterminated string"),
+            }
+        }
+    }
+}
+
+/// Read a PyGram-style JSON array of arrays of strings into a profile built with the
+/// given p and q, treating "*" as filler. Each gram must hold exactly p + q labels,
+/// the first p of them its ancestors.
+pub fn profile_from_pygram_json(json: &str, p: usize, q: usize) -> Result<Profile<String>, PyGramError> {
+    let mut reader = JsonReader{src: json, chars: json.char_indices().peekable()};
+    let flat = reader.array(|r| {
+        r.skip_whitespace();
+        let start = r.position();
+        let gram = r.array(|r| r.string())?;
+        if gram.len() != p + q {
+            return Err(PyGramError{position: start,
+                                   message: format!("expected {} labels in gram, got {}", p + q, gram.len())})
+        }
+        Ok(gram)
+    })?;
+    reader.skip_whitespace();
+    if reader.chars.peek().is_some() {
+        return reader.error("trailing characters")
+    }
+    Ok(Profile::new(unflatten_profile(&flat, p, &PYGRAM_FILLER.to_string()), p, q))
+}
+
+#[cfg(test)]
+mod tests {
+    use default_tree::Tree;
+    use pqgrams::{pqgram_profile, flatten_profile};
+    use super::{unflatten_profile, profile_to_pygram_json, profile_from_pygram_json};
+
+    #[test]
+    fn test_pygram_round_trip() {
+        let tree = Tree::new_str("a").add_node(Tree::new_str("b \"quoted\"")).add_node(Tree::new_str("c\\d"));
+        let prof = pqgram_profile(tree, 2, 3, true);
+        let flat = flatten_profile(&prof, "*".to_string());
+        assert_eq!(unflatten_profile(&flat, 2, &"*".to_string()), prof);
+        let json = profile_to_pygram_json(&prof);
+        assert!(json.starts_with(r#"[["*", "a", "*", "*", "b \"quoted\""]"#));
+        assert_eq!(profile_from_pygram_json(&json, 2, 3).unwrap(), prof);
+    }
+
+    #[test]
+    fn test_pygram_json_errors() {
+        assert!(profile_from_pygram_json("[]", 2, 3).unwrap().is_empty());
+        assert_eq!(profile_from_pygram_json(" [ [\"*\", \"a\"] ] ", 1, 1).unwrap().len(), 1);
+        let err = profile_from_pygram_json("[[\"*\", \"a\"], [\"a\"]]", 1, 1).unwrap_err();
+        assert_eq!((err.position, err.message.as_str()), (13, "expected 2 labels in gram, got 1"));
+        assert_eq!(profile_from_pygram_json("[[\"a\"", 1, 1).unwrap_err().position, 5);
+        assert_eq!(profile_from_pygram_json("[[1]]", 1, 1).unwrap_err().position, 2);
+        assert_eq!(profile_from_pygram_json("[[\"a\" \"b\"]]", 1, 1).unwrap_err().position, 6);
+        assert!(profile_from_pygram_json("[] x", 1, 1).is_err());
+        assert!(profile_from_pygram_json(r#"[["\ud83d"]]"#, 1, 1).is_err());
+        assert!(profile_from_pygram_json(r#"[["\ud83dx"]]"#, 1, 1).is_err());
+        assert!(profile_from_pygram_json(r#"[["\ude00"]]"#, 1, 1).is_err());
+        assert!(profile_from_pygram_json(r#"[["\u+041", "b"]]"#, 1, 1).is_err());
+        assert!(profile_from_pygram_json(r#"[["\u0041", "b"]]"#, 1, 1).is_ok());
+        assert!(profile_from_pygram_json("[\u{a0}[\"a\", \"b\"]]", 1, 1).is_err());
+        assert!(profile_from_pygram_json("[\r\n\t [\"a\", \"b\"]]", 1, 1).is_ok());
+    }
+
+    #[test]
+    fn test_pygram_json_surrogate_pairs() {
+        let prof = profile_from_pygram_json(r#"[["*", "\ud83d\ude00 \u00e9"]]"#, 1, 1).unwrap();
+        assert_eq!(flatten_profile(&prof, "*".to_string()), vec![vec!["*".to_string(), "\u{1F600} \u{e9}".to_string()]]);
+    }
+}
@@ -0,0 +1,112 @@
+use std::{env, fs, io};
+use std::path::Path;
+use pqgrams::{pqgram_profile_ref, LabelledTree, Node, PQGram, ValidGramElement};
+
+/// Set this environment variable to rewrite snapshot files instead of checking them.
+pub const UPDATE_SNAPSHOTS_VAR: &str = "PQGRAMS_UPDATE_SNAPSHOTS";
+
+/// What check_profile_snapshot found.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum SnapshotOutcome {
+    /// There was no snapshot, or updating was requested, so one was written.
+    Written,
+    /// The profile matches the snapshot.
+    Matched,
+    /// The profile differs; one line per differing gram position, counted from 1,
+    /// or a single line if the snapshot was taken with another p or q.
+    Differs(Vec<String>),
+}
+
+/// One line per gram, labels in Debug form and filler as a bare `*`, so a literal
+/// "*" label stays distinguishable.
+fn render_gram<L: ValidGramElement>(gram: &PQGram<L>) -> String {
+    let labels: Vec<String> = gram.ancestors().iter().chain(gram.siblings()).map(|n| match *n {
+        Node::Filler => "*".to_string(),
+        Node::Label(ref l) => format!("{:?}", l),
+    }).collect();
+    labels.join(" ")
+}
+
+fn diff_lines(expected: &str, actual: &str) -> Vec<String> {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut diff = Vec::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => {},
+            (e, a) => diff.push(format!("gram {}: expected {}, got {}", i + 1,
+                                        e.unwrap_or(&"nothing"), a.unwrap_or(&"nothing"))),
+        }
+    }
+    diff
+}
+
+/// Compare a tree's unsorted profile against the snapshot at path, writing it if
+/// there is none yet or UPDATE_SNAPSHOTS_VAR is set. The unsorted order is stable,
+/// so this locks in both the grams a LabelledTree adapter produces and the order of
+/// its children. Commit snapshot files alongside the adapter's tests.
+pub fn check_profile_snapshot<L, T, P>(tree: &T, p: usize, q: usize, path: P) -> io::Result<SnapshotOutcome>
+    where L: ValidGramElement, T: LabelledTree<L>, P: AsRef<Path>
+{
+    let header = format!("p={} q={}", p, q);
+    let grams: String = pqgram_profile_ref(tree, p, q, false).iter().map(|g| render_gram(g) + "\n").collect();
+    let path = path.as_ref();
+    if env::var_os(UPDATE_SNAPSHOTS_VAR).is_some() || !path.exists() {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, format!("{}\n{}", header, grams))?;
+        return Ok(SnapshotOutcome::Written)
+    }
+    let expected = fs::read_to_string(path)?;
+    let (expected_header, expected_grams) = match expected.find('\n') {
+        Some(i) => (&expected[..i], &expected[i + 1..]),
+        None => (expected.as_str(), ""),
+    };
+    // Grams built with other parameters can't be compared line by line.
+    if expected_header != header {
+        return Ok(SnapshotOutcome::Differs(vec![format!("parameters: expected {}, got {}", expected_header, header)]))
+    }
+    let diff = diff_lines(expected_grams, &grams);
+    Ok(if diff.is_empty() { SnapshotOutcome::Matched } else { SnapshotOutcome::Differs(diff) })
+}
+
+/// As check_profile_snapshot, but panics with the differences (or I/O error) for use
+/// in tests.
+pub fn assert_profile_snapshot<L, T, P>(tree: &T, p: usize, q: usize, path: P)
+    where L: ValidGramElement, T: LabelledTree<L>, P: AsRef<Path>
+{
+    let path = path.as_ref();
+    match check_profile_snapshot(tree, p, q, path) {
+        Ok(SnapshotOutcome::Differs(diff)) =>
+            panic!("profile differs from snapshot {} (set {} to update):\n{}", path.display(), UPDATE_SNAPSHOTS_VAR, diff.join("\n")),
+        Ok(_) => {},
+        Err(e) => panic!("could not check snapshot {}: {}", path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use default_tree::Tree;
+    use super::{check_profile_snapshot, assert_profile_snapshot, SnapshotOutcome};
+
+    #[test]
+    fn test_profile_snapshot() {
+        let dir = env::temp_dir().join(format!("pqgrams-snapshot-test-{}", process::id()));
+        let path = dir.join("tree.snap");
+        let tree = Tree::new_str("a").add_node(Tree::new_str("*")).add_node(Tree::new_str("c"));
+        assert_eq!(check_profile_snapshot(&tree, 2, 2, &path).unwrap(), SnapshotOutcome::Written);
+        assert!(fs::read_to_string(&path).unwrap().starts_with("p=2 q=2\n* \"a\" * \"*\"\n"));
+        assert_eq!(check_profile_snapshot(&tree, 2, 2, &path).unwrap(), SnapshotOutcome::Matched);
+        assert_profile_snapshot(&tree, 2, 2, &path);
+        let changed = Tree::new_str("a").add_node(Tree::new_str("*")).add_node(Tree::new_str("d"));
+        match check_profile_snapshot(&changed, 2, 2, &path).unwrap() {
+            SnapshotOutcome::Differs(diff) => assert_eq!(diff[0], "gram 3: expected * \"a\" \"*\" \"c\", got * \"a\" \"*\" \"d\""),
+            outcome => panic!("expected a difference, got {:?}", outcome),
+        }
+        assert_eq!(check_profile_snapshot(&tree, 2, 3, &path).unwrap(),
+                   SnapshotOutcome::Differs(vec!["parameters: expected p=2 q=2, got p=2 q=3".to_string()]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}